@@ -1,13 +1,52 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use chrono::NaiveTime;
 use serde::Deserialize;
 
+use crate::vm::Format;
+
 #[derive(Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct Config {
+    #[serde(rename = "site")]
+    pub sites: Vec<Site>,
+    /// where to write metrics, as `[[sink]]`; `vm`, `influx` and `prometheus` are added to
+    /// these, and if there are none at all, metrics go to stdout
+    #[serde(rename = "sink", default)]
+    pub sinks: Vec<Sink>,
+    pub vm: Option<Vm>,
+    pub influx: Option<Influx>,
+    #[serde(default)]
+    pub forecast: Forecast,
+    pub score: Option<Score>,
+    pub prometheus: Option<Prometheus>,
+}
+
+impl Config {
+    /// Every sink: the `[[sink]]` list, then `vm`, `influx` and `prometheus`.
+    pub fn take_sinks(&mut self) -> Vec<Sink> {
+        let mut sinks = std::mem::take(&mut self.sinks);
+        sinks.extend(self.vm.take().map(Sink::Vm));
+        sinks.extend(self.influx.take().map(Sink::Influx));
+        sinks.extend(self.prometheus.take().map(Sink::Prometheus));
+        sinks
+    }
+}
+
+/// A named location, and the services to run for it; `[[site]]` in the toml.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Site {
+    /// added as the `site` label to everything produced for this site
+    pub name: String,
     pub loc: Loc,
     pub owm: Option<Owm>,
     pub met: Option<Met>,
-    #[serde(rename = "soliscloud")]
-    pub solis_cloud: Option<Solis>,
+    /// any number of accounts, as `[[site.soliscloud]]`
+    #[serde(rename = "soliscloud", default)]
+    pub solis_cloud: Vec<Solis>,
+    pub sun: Option<Sun>,
 }
 
 #[derive(Copy, Clone, Deserialize)]
@@ -15,21 +54,98 @@ pub struct Config {
 pub struct Loc {
     pub lat: f64,
     pub lon: f64,
-    // pub dec_deg: u8,
-    // pub az_deg: u8,
-    // pub kwp: f64,
+    /// panel tilt: 0 is flat, 90 is vertical
+    pub dec_deg: Option<f64>,
+    /// panel direction, degrees clockwise from north: 180 is south-facing
+    pub az_deg: Option<f64>,
+    /// array peak power, kW
+    pub kwp: Option<f64>,
+}
+
+#[derive(Copy, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Forecast {
+    /// UTC time of day at which "today" becomes "tomorrow", e.g. `"21:30:00"`
+    #[serde(default = "default_rollover")]
+    pub rollover: NaiveTime,
+}
+
+impl Default for Forecast {
+    fn default() -> Self {
+        Forecast {
+            rollover: default_rollover(),
+        }
+    }
+}
+
+fn default_rollover() -> NaiveTime {
+    NaiveTime::from_hms_opt(21, 30, 0).expect("valid time")
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Score {
+    /// where forecasts are kept until they can be scored, e.g. `score-state.json`
+    pub state: PathBuf,
+}
+
+/// One destination for metrics, e.g. `type = "file"`.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum Sink {
+    Stdout {
+        #[serde(default)]
+        format: Format,
+    },
+    File(File),
+    Vm(Vm),
+    Influx(Influx),
+    Prometheus(Prometheus),
+}
+
+/// Appended to, and rotated to `path.1`, `path.2` etc. when it gets too big.
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct File {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub format: Format,
+    #[serde(default = "default_file_max_bytes")]
+    pub max_bytes: u64,
+    /// how many rotated files to keep
+    #[serde(default = "default_file_keep")]
+    pub keep: u32,
+}
+
+/// Serve the latest values for scraping, in daemon mode.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Prometheus {
+    /// e.g. `0.0.0.0:9464`; served at `/metrics`
+    pub listen: SocketAddr,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Sun {
+    #[serde(default = "default_sun_interval")]
+    pub interval_secs: u64,
 }
 
 #[derive(Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct Owm {
     pub key: String,
+    #[serde(default = "default_owm_interval")]
+    pub interval_secs: u64,
 }
 
 #[derive(Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct Met {
     pub key: String,
+    #[serde(default = "default_met_interval")]
+    pub interval_secs: u64,
 }
 
 #[derive(Deserialize)]
@@ -38,4 +154,198 @@ pub struct Solis {
     pub api: String,
     pub key: String,
     pub secret: String,
+    #[serde(default = "default_solis_interval")]
+    pub interval_secs: u64,
+    /// how often to refresh the list of inverters, in case they're added or replaced
+    #[serde(default = "default_solis_rediscover")]
+    pub rediscover_secs: u64,
+    /// usable capacity of each inverter's battery, kWh, for counting cycles
+    pub battery_kwh: Option<f64>,
+    /// for rate limiting and server errors; doubling the delay each time
+    #[serde(default = "default_solis_attempts")]
+    pub attempts: u32,
+    #[serde(default = "default_solis_retry_delay")]
+    pub retry_delay_ms: u64,
+    /// allow `disport-data slots set` to change settings on the inverters
+    #[serde(default)]
+    pub allow_control: bool,
+    /// how long a changed setting has to read back, checked every `retry_delay_ms`
+    #[serde(default = "default_solis_readback_timeout")]
+    pub readback_timeout_ms: u64,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Vm {
+    /// e.g. `http://localhost:8428/api/v1/import`
+    pub url: String,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    /// used instead of `user` and `password`, if both are set
+    pub bearer: Option<String>,
+    /// where to keep lines which couldn't be delivered, to be sent with the next batch
+    pub spool: Option<PathBuf>,
+    /// the oldest lines are dropped beyond this
+    #[serde(default = "default_spool_max_bytes")]
+    pub spool_max_bytes: u64,
+    #[serde(default = "default_vm_attempts")]
+    pub attempts: u32,
+    #[serde(default = "default_vm_retry_delay")]
+    pub retry_delay_ms: u64,
+}
+
+/// An InfluxDB v2 server, written to with line protocol.
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Influx {
+    /// e.g. `http://localhost:8086`; `/api/v2/write` is added
+    pub url: String,
+    pub org: String,
+    pub bucket: String,
+    pub token: String,
+    pub spool: Option<PathBuf>,
+    /// the oldest lines are dropped beyond this
+    #[serde(default = "default_spool_max_bytes")]
+    pub spool_max_bytes: u64,
+    #[serde(default = "default_vm_attempts")]
+    pub attempts: u32,
+    #[serde(default = "default_vm_retry_delay")]
+    pub retry_delay_ms: u64,
+}
+
+fn default_file_max_bytes() -> u64 {
+    64 * 1024 * 1024
+}
+
+fn default_file_keep() -> u32 {
+    4
+}
+
+fn default_spool_max_bytes() -> u64 {
+    64 * 1024 * 1024
+}
+
+fn default_vm_attempts() -> u32 {
+    3
+}
+
+fn default_vm_retry_delay() -> u64 {
+    1_000
+}
+
+// only used in daemon mode; a single run ignores these
+fn default_owm_interval() -> u64 {
+    10 * 60
+}
+
+fn default_met_interval() -> u64 {
+    3 * 60 * 60
+}
+
+fn default_solis_interval() -> u64 {
+    60
+}
+
+fn default_solis_rediscover() -> u64 {
+    6 * 60 * 60
+}
+
+fn default_solis_attempts() -> u32 {
+    3
+}
+
+fn default_solis_retry_delay() -> u64 {
+    2_000
+}
+
+fn default_solis_readback_timeout() -> u64 {
+    120_000
+}
+
+fn default_sun_interval() -> u64 {
+    5 * 60
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::*;
+
+    fn parse(toml: &str) -> Result<Config> {
+        let config = ::config::Config::builder()
+            .add_source(::config::File::from_str(toml, ::config::FileFormat::Toml))
+            .build()?;
+        Ok(serde_path_to_error::deserialize(config)?)
+    }
+
+    const SITE: &str = r#"
+        [[site]]
+        name = "home"
+        loc = { lat = 51.5, lon = 0.0 }
+    "#;
+
+    #[test]
+    fn test_sinks() -> Result<()> {
+        let mut config = parse(&format!(
+            r#"{SITE}
+            [[sink]]
+            type = "stdout"
+            format = "influx"
+
+            [[sink]]
+            type = "file"
+            path = "/var/lib/disport/metrics.jsonl"
+            keep = 2
+
+            [[sink]]
+            type = "influx"
+            url = "http://localhost:8086"
+            org = "home"
+            bucket = "solar"
+            token = "sekrit"
+
+            [vm]
+            url = "http://localhost:8428/api/v1/import"
+
+            [prometheus]
+            listen = "127.0.0.1:9464"
+            "#
+        ))?;
+        let sinks = config.take_sinks();
+        assert!(matches!(
+            sinks[0],
+            Sink::Stdout {
+                format: Format::Influx
+            }
+        ));
+        let Sink::File(file) = &sinks[1] else {
+            panic!("not a file");
+        };
+        assert_eq!(file.format, Format::Vm);
+        assert_eq!((file.keep, file.max_bytes), (2, default_file_max_bytes()));
+        assert!(matches!(&sinks[2], Sink::Influx(i) if i.bucket == "solar"));
+        assert!(matches!(&sinks[3], Sink::Vm(vm) if vm.url.ends_with("/import")));
+        assert!(matches!(&sinks[4], Sink::Prometheus(p) if p.listen.port() == 9464));
+        assert_eq!(sinks.len(), 5);
+        assert!(config.take_sinks().is_empty());
+
+        // left to `Sinks::new` to fall back to stdout
+        assert!(parse(SITE)?.take_sinks().is_empty());
+
+        let unknown = format!("{SITE}\n[[sink]]\ntype = \"carrier-pigeon\"\n");
+        assert!(parse(&unknown).is_err());
+        let misspelt = format!("{SITE}\n[[sink]]\ntype = \"file\"\npath = \"x\"\nkeeep = 1\n");
+        assert!(parse(&misspelt).is_err());
+        let misspelt = format!("{SITE}\n[[sink]]\ntype = \"stdout\"\nfromat = \"influx\"\n");
+        assert!(parse(&misspelt).is_err());
+        let stdout = format!("{SITE}\n[[sink]]\ntype = \"stdout\"\nformat = \"influx\"\n");
+        assert!(matches!(
+            parse(&stdout)?.take_sinks()[..],
+            [Sink::Stdout {
+                format: Format::Influx
+            }]
+        ));
+        Ok(())
+    }
 }
@@ -0,0 +1,166 @@
+//! Labels shared by every weather source, so forecasts line up in queries.
+//!
+//! A "period" runs from the rollover time (default 21:30 UTC) yesterday to the rollover
+//! time today; roughly sunset, so "today" means today's daylight even for a forecast
+//! made late in the evening. Every forecast point gets:
+//!
+//! * `source`: where the forecast came from, `met`, `owm`, etc.
+//! * `period`: the day being talked about, relative to when the forecast was issued;
+//!   `0` for the current period, `1` for the next, etc.
+//! * `time`: the time of day of the target, `HH:MM` in UTC, after rounding to the hour
+//! * `advance`: how far in advance the forecast was made, in whole hours; `0h` is the
+//!   source's idea of "now", negative for points before the issue time
+//!
+//! ..so `forecast_cloud_cover_pct{period="0", time="14:00", source="met", advance="3h"}`
+//! is what the Met Office thought about 2pm, at 11am, and
+//! `avg(forecast_cloud_cover_pct{time="14:00", advance="0h"})` is everyone's opinion of
+//! the actual value.
+
+use anyhow::Result;
+use chrono::{DateTime, DurationRound, NaiveTime, TimeDelta, Utc};
+
+use crate::config;
+use crate::vm::{FullName, Obs};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Quantity {
+    TempC,
+    CloudCoverPct,
+    Uvi,
+    WindSpeedMs,
+    HumidityPct,
+    PrecipProbPct,
+}
+
+impl Quantity {
+    pub fn metric_name(self) -> &'static str {
+        match self {
+            Quantity::TempC => "forecast_temp_c",
+            Quantity::CloudCoverPct => "forecast_cloud_cover_pct",
+            Quantity::Uvi => "forecast_uvi",
+            Quantity::WindSpeedMs => "forecast_wind_speed_ms",
+            Quantity::HumidityPct => "forecast_humidity_pct",
+            Quantity::PrecipProbPct => "forecast_precip_prob_pct",
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Labeller {
+    rollover: NaiveTime,
+}
+
+impl Labeller {
+    pub fn new(config: &config::Forecast) -> Self {
+        Labeller {
+            rollover: config.rollover,
+        }
+    }
+
+    /// The hour the observation is filed under, and its `period`, `time` and `advance` labels.
+    pub fn labels(
+        &self,
+        issued: DateTime<Utc>,
+        target: DateTime<Utc>,
+    ) -> (DateTime<Utc>, [(&'static str, String); 3]) {
+        let target = round_to_hour(target);
+        let period = (self.period_date(target) - self.period_date(issued)).num_days();
+        let advance = ((target - issued).num_seconds() as f64 / 3600.).round() as i64;
+        (
+            target,
+            [
+                ("period", period.to_string()),
+                ("time", target.format("%H:%M").to_string()),
+                ("advance", format!("{advance}h")),
+            ],
+        )
+    }
+
+    fn period_date(&self, when: DateTime<Utc>) -> chrono::NaiveDate {
+        let until_midnight = TimeDelta::days(1) - (self.rollover - NaiveTime::MIN);
+        (when + until_midnight).date_naive()
+    }
+}
+
+pub fn round_to_hour(when: DateTime<Utc>) -> DateTime<Utc> {
+    when.duration_round(TimeDelta::hours(1))
+        .expect("timestamps are nowhere near overflowing")
+}
+
+/// Collects the comparable subset of one forecast from one source.
+pub struct Forecast {
+    labeller: Labeller,
+    source: &'static str,
+    issued: DateTime<Utc>,
+    out: Vec<(FullName, Obs)>,
+}
+
+impl Forecast {
+    pub fn new(labeller: Labeller, source: &'static str, issued: DateTime<Utc>) -> Self {
+        Forecast {
+            labeller,
+            source,
+            issued,
+            out: Vec::with_capacity(64),
+        }
+    }
+
+    pub fn add(&mut self, target: DateTime<Utc>, quantity: Quantity, value: f64) -> Result<()> {
+        if value.is_nan() {
+            return Ok(());
+        }
+        let (when, labels) = self.labeller.labels(self.issued, target);
+        let labels = labels
+            .into_iter()
+            .chain([("source", self.source.to_string())]);
+        self.out.push((
+            FullName::new(quantity.metric_name(), labels)?,
+            Obs::new(value, when),
+        ));
+        Ok(())
+    }
+
+    pub fn finish(self) -> Vec<(FullName, Obs)> {
+        self.out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+
+    fn labeller() -> Labeller {
+        Labeller::new(&config::Forecast::default())
+    }
+
+    #[test]
+    fn test_labels() {
+        let issued = Utc.with_ymd_and_hms(2023, 10, 6, 11, 5, 0).unwrap();
+        let target = Utc.with_ymd_and_hms(2023, 10, 6, 13, 50, 0).unwrap();
+        let (when, labels) = labeller().labels(issued, target);
+        assert_eq!(when, Utc.with_ymd_and_hms(2023, 10, 6, 14, 0, 0).unwrap());
+        assert_eq!(
+            labels.map(|(_, v)| v),
+            ["0".to_string(), "14:00".to_string(), "3h".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_rollover() {
+        let l = labeller();
+        let issued = Utc.with_ymd_and_hms(2023, 10, 6, 22, 0, 0).unwrap();
+        // after rollover, tomorrow afternoon is already period 0
+        let target = Utc.with_ymd_and_hms(2023, 10, 7, 14, 0, 0).unwrap();
+        assert_eq!(l.labels(issued, target).1[0].1, "0");
+        let target = Utc.with_ymd_and_hms(2023, 10, 7, 22, 0, 0).unwrap();
+        assert_eq!(l.labels(issued, target).1[0].1, "1");
+
+        let issued = Utc.with_ymd_and_hms(2023, 10, 6, 18, 0, 0).unwrap();
+        let target = Utc.with_ymd_and_hms(2023, 10, 6, 15, 0, 0).unwrap();
+        let (_, labels) = l.labels(issued, target);
+        assert_eq!(labels[0].1, "0");
+        assert_eq!(labels[2].1, "-3h");
+    }
+}
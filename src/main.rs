@@ -1,43 +1,141 @@
 use std::time::Duration;
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use chrono::{NaiveDate, Utc};
+use log::{info, warn};
 use reqwest::Client;
+use tokio::time::Instant;
 
 use crate::config::Config;
 use crate::vm::{FullName, Obs};
 
 mod config;
+mod forecast;
 mod met;
 mod owm;
+mod prom;
+mod pv;
+mod score;
+mod solar;
 mod soliscloud;
+#[cfg(test)]
+mod testing;
 mod vm;
 
 enum Service {
-    SolisCloud(soliscloud::Service),
+    SolisCloud(Box<soliscloud::Service>),
     Met(met::Service),
     Owm(owm::Service),
+    Sun(solar::Service),
 }
 
 impl Service {
-    async fn run(&self, http: &Client) -> Result<Vec<(FullName, Obs)>> {
+    async fn run(&mut self, http: &Client) -> Result<Vec<(FullName, Obs)>> {
         match self {
-            Service::SolisCloud(svc) => soliscloud::run(http, &svc).await,
-            Service::Met(svc) => met::run(http, &svc).await,
-            Service::Owm(svc) => owm::run(http, &svc).await,
+            Service::SolisCloud(svc) => soliscloud::run(svc).await,
+            Service::Met(svc) => met::run(http, svc).await,
+            Service::Owm(svc) => owm::run(http, svc).await,
+            Service::Sun(svc) => solar::run(svc, Utc::now()),
         }
     }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Service::SolisCloud(_) => "soliscloud",
+            Service::Met(_) => "met",
+            Service::Owm(_) => "owm",
+            Service::Sun(_) => "sun",
+        }
+    }
+}
+
+enum Mode {
+    /// poll everything once, and exit
+    Once,
+    Daemon,
+    /// fetch SolisCloud history for these dates, inclusive
+    Backfill(NaiveDate, NaiveDate),
+    /// show the SolisCloud timed charge slots, or change them
+    Slots {
+        set: Option<soliscloud::control::Slots>,
+        dry_run: bool,
+    },
+}
+
+/// One `[[site]]` from the config.
+struct Site {
+    name: String,
+    panels: Option<pv::Panels>,
+}
+
+/// A service for a site, and how often it should be polled in daemon mode.
+struct Scheduled {
+    site: usize,
+    svc: Service,
+    interval: Duration,
+}
+
+/// Metrics computed from the output of the services, rather than fetched.
+struct Derived {
+    scorer: Option<score::Scorer>,
+}
+
+impl Derived {
+    /// Add derived metrics, and the `site` label, to a service's output.
+    fn extend(&mut self, site: &Site, produced: &mut Vec<(FullName, Obs)>) -> Result<()> {
+        let now = Utc::now();
+        if let Some(panels) = &site.panels {
+            produced.extend(panels.forecast(produced, now)?);
+        }
+        for (name, _) in produced.iter_mut() {
+            name.add_label("site", &site.name)?;
+        }
+        if let Some(scorer) = &mut self.scorer {
+            produced.extend(scorer.observe(produced, now)?);
+        }
+        Ok(())
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     pretty_env_logger::init_timed();
 
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let args = args.iter().map(|s| s.as_str()).collect::<Vec<_>>();
+    let mode = match args.as_slice() {
+        [] => Mode::Once,
+        ["daemon"] => Mode::Daemon,
+        ["backfill", from, to] => {
+            let date = |s: &str| {
+                s.parse::<NaiveDate>()
+                    .with_context(|| anyhow!("parsing {s:?} as a date"))
+            };
+            Mode::Backfill(date(from)?, date(to)?)
+        }
+        ["slots"] => Mode::Slots {
+            set: None,
+            dry_run: false,
+        },
+        ["slots", "set", value, rest @ ..] if rest.is_empty() || rest == ["--dry-run"] => {
+            Mode::Slots {
+                set: Some(value.parse()?),
+                dry_run: !rest.is_empty(),
+            }
+        }
+        other => bail!(
+            "usage: disport-data [daemon | backfill FROM TO | slots [set VALUE [--dry-run]]], \
+             not {other:?}"
+        ),
+    };
+
     let config = ::config::Config::builder()
         .add_source(::config::File::with_name(".env.toml"))
         .add_source(::config::Environment::with_prefix("DISPORT").separator("_"))
         .build()?;
 
-    let config: Config = serde_path_to_error::deserialize(config)?;
+    let mut config: Config = serde_path_to_error::deserialize(config)?;
+    let sinks = config.take_sinks();
 
     let http = reqwest::ClientBuilder::default()
         .user_agent(concat!(
@@ -48,35 +146,195 @@ async fn main() -> Result<()> {
         .timeout(Duration::from_secs(30))
         .build()?;
 
+    let labeller = forecast::Labeller::new(&config.forecast);
+
+    let mut sites = Vec::with_capacity(config.sites.len());
     let mut svcs = Vec::new();
 
-    if let Some(solis_cloud) = config.solis_cloud {
-        svcs.push(Service::SolisCloud(
-            soliscloud::warmup(&http, solis_cloud).await?,
-        ));
+    for site in config.sites {
+        ensure!(
+            !sites.iter().any(|s: &Site| s.name == site.name),
+            "duplicate site name: {:?}",
+            site.name
+        );
+        let idx = sites.len();
+        sites.push(Site {
+            name: site.name,
+            panels: pv::Panels::from_loc(&site.loc),
+        });
+        let mut schedule = |svc, interval_secs| {
+            svcs.push(Scheduled {
+                site: idx,
+                svc,
+                interval: Duration::from_secs(interval_secs),
+            })
+        };
+
+        for solis_cloud in site.solis_cloud {
+            let interval = solis_cloud.interval_secs;
+            // a daemon shouldn't give up because SolisCloud is down when it starts
+            let svc = match mode {
+                Mode::Daemon => soliscloud::Service::new(&http, solis_cloud),
+                _ => soliscloud::warmup(&http, solis_cloud).await?,
+            };
+            schedule(Service::SolisCloud(Box::new(svc)), interval);
+        }
+        if let Some(met) = site.met {
+            let svc = met::Service {
+                loc: site.loc,
+                key: met.key,
+                labeller,
+            };
+            schedule(Service::Met(svc), met.interval_secs);
+        }
+        if let Some(owm) = site.owm {
+            let svc = owm::Service {
+                loc: site.loc,
+                key: owm.key,
+                labeller,
+            };
+            schedule(Service::Owm(svc), owm.interval_secs);
+        }
+        if let Some(sun) = site.sun {
+            let svc = solar::Service { loc: site.loc };
+            schedule(Service::Sun(svc), sun.interval_secs);
+        }
     }
-    if let Some(met) = config.met {
-        svcs.push(Service::Met(met::Service {
-            loc: config.loc,
-            key: met.key,
-        }));
+
+    let poll = match mode {
+        Mode::Daemon => svcs.iter().map(|s| s.interval).max(),
+        _ => None,
+    };
+    let sinks = vm::Sinks::new(&http, sinks, poll).await?;
+    let panels = sites
+        .iter()
+        .filter_map(|s| Some((s.name.clone(), s.panels?)))
+        .collect();
+    let mut derived = Derived {
+        scorer: config
+            .score
+            .as_ref()
+            .map(|score| score::Scorer::load(score, panels))
+            .transpose()?,
+    };
+
+    match mode {
+        Mode::Once => (),
+        Mode::Daemon => return run_daemon(&http, &sites, &mut svcs, &sinks, &mut derived).await,
+        Mode::Backfill(from, to) => return run_backfill(&sites, &svcs, &sinks, from, to).await,
+        Mode::Slots { set, dry_run } => return run_slots(&sites, &svcs, set, dry_run).await,
     }
-    if let Some(owm) = config.owm {
-        svcs.push(Service::Owm(owm::Service {
-            loc: config.loc,
-            key: owm.key,
-        }));
+
+    let mut all = Vec::with_capacity(1024);
+    for sched in &mut svcs {
+        let mut produced = sched.svc.run(&http).await?;
+        derived.extend(&sites[sched.site], &mut produced)?;
+        all.extend(produced);
     }
+    sinks.write(&all).await
+}
 
-    let mut buf = Vec::with_capacity(4096);
-    for svc in &svcs {
-        let produced = svc.run(&http).await?;
-        for (name, obs) in produced {
-            vm::write_metric(&mut buf, &name, &[obs])?;
+/// Poll every service forever, each on its own interval, writing metrics as they arrive.
+///
+/// A failing service, or failing to derive metrics from it, is logged and retried at its next
+/// slot; it doesn't take the others down.
+async fn run_daemon(
+    http: &Client,
+    sites: &[Site],
+    svcs: &mut [Scheduled],
+    sinks: &vm::Sinks,
+    derived: &mut Derived,
+) -> Result<()> {
+    ensure!(!svcs.is_empty(), "no services configured");
+    for sched in svcs.iter() {
+        info!(
+            "polling {} for {} every {:?}",
+            sched.svc.name(),
+            sites[sched.site].name,
+            sched.interval
+        );
+    }
+
+    let mut due = vec![Instant::now(); svcs.len()];
+    loop {
+        let (idx, when) = due
+            .iter()
+            .copied()
+            .enumerate()
+            .min_by_key(|(_, when)| *when)
+            .expect("non-empty");
+        tokio::time::sleep_until(when).await;
+
+        let sched = &mut svcs[idx];
+        let site = &sites[sched.site];
+        match sched.svc.run(http).await {
+            Ok(mut produced) => {
+                if let Err(e) = derived.extend(site, &mut produced) {
+                    warn!(
+                        "deriving from {} for {}: {e:?}",
+                        sched.svc.name(),
+                        site.name
+                    );
+                } else if let Err(e) = sinks.write(&produced).await {
+                    warn!("{} for {}: {e:?}", sched.svc.name(), site.name);
+                }
+            }
+            Err(e) => warn!("{} for {} failed: {e:?}", sched.svc.name(), site.name),
         }
+
+        // if a run overran its slot, skip ahead instead of firing repeatedly to catch up
+        due[idx] = (when + sched.interval).max(Instant::now());
     }
+}
 
-    println!("{}", String::from_utf8_lossy(&buf));
+/// Fetch history for every SolisCloud account, a day (or month, or year) at a time.
+async fn run_backfill(
+    sites: &[Site],
+    svcs: &[Scheduled],
+    sinks: &vm::Sinks,
+    from: NaiveDate,
+    to: NaiveDate,
+) -> Result<()> {
+    ensure!(from <= to, "backfill from {from} is after {to}");
+    for sched in svcs {
+        let Service::SolisCloud(svc) = &sched.svc else {
+            continue;
+        };
+        let site = &sites[sched.site];
+        let mut backfill = soliscloud::backfill::Backfill::new(svc).await?;
+        for span in soliscloud::backfill::spans(from, to) {
+            let mut produced = backfill.fetch(span).await?;
+            for (name, _) in &mut produced {
+                name.add_label("site", &site.name)?;
+            }
+            sinks.write(&produced).await?;
+        }
+    }
+    Ok(())
+}
 
+/// Print, or change, the timed charge slots for every SolisCloud account.
+async fn run_slots(
+    sites: &[Site],
+    svcs: &[Scheduled],
+    set: Option<soliscloud::control::Slots>,
+    dry_run: bool,
+) -> Result<()> {
+    for sched in svcs {
+        let Service::SolisCloud(svc) = &sched.svc else {
+            continue;
+        };
+        let site = &sites[sched.site].name;
+        match &set {
+            Some(slots) => soliscloud::control::set(svc, slots, dry_run)
+                .await
+                .with_context(|| anyhow!("site {site:?}"))?,
+            None => {
+                for (sn, slots) in soliscloud::control::read(svc).await? {
+                    println!("{site} {sn} {slots}");
+                }
+            }
+        }
+    }
     Ok(())
 }
@@ -1,6 +1,9 @@
 use crate::config::Loc;
+use crate::forecast::{Forecast, Labeller, Quantity};
 use crate::vm::{FullName, Obs};
 use anyhow::{anyhow, bail, Result};
+use chrono::{TimeZone, Utc};
+use convert_case::{Case, Casing};
 use serde::Deserialize;
 use serde_aux::prelude::*;
 use std::ops::Add;
@@ -10,6 +13,7 @@ use time::{Duration, OffsetDateTime};
 pub struct Service {
     pub loc: Loc,
     pub key: String,
+    pub labeller: Labeller,
 }
 
 pub async fn run(http: &reqwest::Client, svc: &Service) -> Result<Vec<(FullName, Obs)>> {
@@ -26,37 +30,7 @@ pub async fn run(http: &reqwest::Client, svc: &Service) -> Result<Vec<(FullName,
         .json()
         .await?;
     let forecast = MetForecast::from_response(resp)?;
-    println!("{station:?} - {forecast:?}");
-
-    Ok(Vec::new())
-
-    // a period runs from 21:30 yesterday -> 21:30 today
-    // chosen due to sunset. or just use actual sunset?
-    // probably 21:30 local. When do the met 3h forecasts happen? Want to not line up with those to some extent.
-    // otoh, forecast at 9pm isn't particularly relevant; we're focusing on the 6am-6pm period.
-
-    // period: the day we're talking about, either today (before sunset) (0) or tomorrow (1), etc.
-    // time: the time on that day (timezone?)
-    // source: where the forecast came from, met, owm, etc.
-    // advance: how far in advance the forecast was made, 3h, 6h, etc. Round to nearest hour?
-    // value: the actual value
-    // cloud_cover{period: 0, time: 14:00, source: met, advance: 3h} 77%
-
-    // Can we query this? mean(cloud_cover(period=0, time=14:00, advance: 0h)) is the average of everyone's
-    // actual value, where advance:0 means actual?
-
-    // negative advances, does anyone change their actual after the fact?
-    // most apis probably just don't have actual
-
-    // Is this a query you can write? `select mean(cloud_cover(period=0, time=14:00, advance: 1-3h))`
-    // Is this a query you can write? `graph cloud_cover(period=0, time=14:00, source: met) by advance`
-
-    // in theory you can work out the advance from the observation time in influx. is that easier or harder to query?
-
-    // round all times to the nearest hour?
-
-    // cloud cover is a derived metric, should we be met_cloud_cover, owm_cloud_cover; then the derived value?
-    // or are we going to re-derive it from the json if it's boned?
+    forecast.observations(&station, svc.labeller)
 }
 
 #[derive(Deserialize)]
@@ -111,7 +85,7 @@ pub fn find_nearest(target: &geoutils::Location) -> Result<MetLocation> {
         let lon = cand.longitude.parse::<f64>()?;
         let loc = geoutils::Location::new(lat, lon);
         let dist = loc
-            .distance_to(&target)
+            .distance_to(target)
             .map_err(|e| anyhow!("{e}"))?
             .meters();
         if dist < closest_dist {
@@ -296,6 +270,21 @@ impl Weather {
             _ => bail!("unknown weather code {}", code),
         }))
     }
+
+    /// Rough guess at the cloud cover implied by the weather type, to compare with
+    /// providers which actually report it.
+    pub fn cloud_cover_pct(&self) -> f64 {
+        use Weather::*;
+        match self {
+            Clear => 0.,
+            PartlyCloudy => 50.,
+            Mist | Cloudy => 80.,
+            TraceRain | LightRainShower | SleetShower | HailShower | LightSnowShower => 70.,
+            HeavyRainShower | HeavySnowShower | ThunderShower => 85.,
+            Fog | Overcast | LightRain | Drizzle | HeavyRain | Sleet | Hail | LightSnow
+            | HeavySnow | Thunder => 100.,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -305,6 +294,69 @@ pub struct MetForecast {
 }
 
 impl MetForecast {
+    /// One metric per `MetObs` field per forecast point, plus the shared `forecast_*` subset.
+    ///
+    /// The `met_*` series carry `station` / `station_name` from the site list, and the
+    /// `period` / `time` / `advance` labels from the `forecast` module, with `data_date`
+    /// as the issue time.
+    pub fn observations(
+        &self,
+        station: &MetLocation,
+        labeller: Labeller,
+    ) -> Result<Vec<(FullName, Obs)>> {
+        let issued = to_chrono(self.data_date)?;
+        let mut shared = Forecast::new(labeller, "met", issued);
+        let mut ret = Vec::with_capacity(self.forecast.len() * 12);
+        for (target, obs) in &self.forecast {
+            let target = to_chrono(*target)?;
+            let (when, labels) = labeller.labels(issued, target);
+            let labels = [
+                ("station", station.id.clone()),
+                ("station_name", station.name.clone()),
+            ]
+            .into_iter()
+            .chain(labels)
+            .collect::<Vec<_>>();
+            let mut emit = |name: &str, value: f64, extra: Option<(&str, String)>| {
+                // NaN is "unknown", and can't be represented in the json anyway
+                if value.is_nan() {
+                    return Ok(());
+                }
+                let labels = labels.iter().cloned().chain(extra);
+                ret.push((
+                    FullName::new(format!("met_{name}"), labels)?,
+                    Obs::new(value, when),
+                ));
+                Ok::<_, anyhow::Error>(())
+            };
+
+            emit("temp_c", obs.temp_c, None)?;
+            emit("feels_like_c", obs.feels_like_c, None)?;
+            emit("wind_mph", obs.wind_mph, None)?;
+            emit("wind_gust_mph", obs.wind_gust_mph, None)?;
+            if let Some(deg) = compass_to_deg(&obs.wind_dir) {
+                emit("wind_dir_deg", deg, None)?;
+            }
+            emit("rel_humidity_pct", obs.rel_humidity, None)?;
+            emit("visibility_km", obs.visibility_km, None)?;
+            emit("precip_prob_pct", obs.precip_prob, None)?;
+            emit("max_uv", obs.max_uv, None)?;
+            if let Some(weather) = &obs.weather {
+                let name = format!("{weather:?}").to_case(Case::Snake);
+                emit("weather", 1., Some(("weather", name)))?;
+                shared.add(target, Quantity::CloudCoverPct, weather.cloud_cover_pct())?;
+            }
+
+            shared.add(target, Quantity::TempC, obs.temp_c)?;
+            shared.add(target, Quantity::Uvi, obs.max_uv)?;
+            shared.add(target, Quantity::WindSpeedMs, obs.wind_mph * MPH_TO_MS)?;
+            shared.add(target, Quantity::HumidityPct, obs.rel_humidity)?;
+            shared.add(target, Quantity::PrecipProbPct, obs.precip_prob)?;
+        }
+        ret.extend(shared.finish());
+        Ok(ret)
+    }
+
     pub fn from_response(resp: WeatherResponse) -> Result<MetForecast> {
         let mut forecast = Vec::new();
         let data_date = OffsetDateTime::parse(&resp.site_rep.dv.data_date, &Iso8601::DEFAULT)?;
@@ -314,7 +366,7 @@ impl MetForecast {
                     .value
                     .strip_suffix("Z")
                     .ok_or(anyhow!("bad period value"))?;
-                let time = time::Date::parse(&value, &Iso8601::DEFAULT)?
+                let time = time::Date::parse(value, &Iso8601::DEFAULT)?
                     .midnight()
                     .assume_utc()
                     .add(Duration::minutes(i64::from(rep.mins)));
@@ -350,6 +402,23 @@ impl MetForecast {
     }
 }
 
+const MPH_TO_MS: f64 = 0.44704;
+
+fn to_chrono(when: OffsetDateTime) -> Result<chrono::DateTime<Utc>> {
+    Utc.timestamp_opt(when.unix_timestamp(), 0)
+        .single()
+        .ok_or_else(|| anyhow!("invalid time: {when}"))
+}
+
+fn compass_to_deg(dir: &str) -> Option<f64> {
+    let points = [
+        "N", "NNE", "NE", "ENE", "E", "ESE", "SE", "SSE", "S", "SSW", "SW", "WSW", "W", "WNW",
+        "NW", "NNW",
+    ];
+    let idx = points.iter().position(|p| *p == dir)?;
+    Some(idx as f64 * 22.5)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -368,4 +437,32 @@ mod tests {
         let forecast = MetForecast::from_response(resp).unwrap();
         assert_eq!(forecast.forecast[0].0.unix_timestamp(), 1696604400);
     }
+
+    #[test]
+    fn test_observations() {
+        let resp: WeatherResponse =
+            serde_json::from_str(include_str!("../tests/ref/met-folkes.json")).unwrap();
+        let forecast = MetForecast::from_response(resp).unwrap();
+        let station = find_nearest(&geoutils::Location::new(51.0809, 1.1711)).unwrap();
+        let labeller = Labeller::new(&crate::config::Forecast::default());
+        let obs = forecast.observations(&station, labeller).unwrap();
+        let (name, first) = obs.iter().find(|(n, _)| n.name() == "met_temp_c").unwrap();
+        assert_eq!(name.label("time"), Some("15:00"));
+        assert_eq!(name.label("advance"), Some("-3h"));
+        assert_eq!(first.value(), 20.);
+        assert_eq!(name.label("period"), Some("0"));
+        assert!(obs
+            .iter()
+            .any(|(n, _)| n.name() == "met_wind_dir_deg" && n.label("advance") == Some("0h")));
+        let (_, cloud) = obs
+            .iter()
+            .find(|(n, _)| {
+                n.name() == "forecast_cloud_cover_pct"
+                    && n.label("source") == Some("met")
+                    && n.label("advance") == Some("-3h")
+            })
+            .unwrap();
+        // weather type 7, "Cloudy"
+        assert_eq!(cloud.value(), 80.);
+    }
 }
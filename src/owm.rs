@@ -1,17 +1,20 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, TimeZone, Utc};
 use reqwest::Client;
-use serde_json::Value;
+use serde::Deserialize;
 
 use crate::config::Loc;
+use crate::forecast::{round_to_hour, Forecast, Labeller, Quantity};
 use crate::vm::{FullName, Obs};
 
 pub struct Service {
     pub loc: Loc,
     pub key: String,
+    pub labeller: Labeller,
 }
 
 pub async fn run(http: &Client, svc: &Service) -> Result<Vec<(FullName, Obs)>> {
-    let resp: Value = http
+    let resp: OneCall = http
         .get(format!(
             "https://api.openweathermap.org/data/3.0/onecall?lat={lat}&lon={lon}&appid={key}",
             lat = svc.loc.lat,
@@ -23,6 +26,355 @@ pub async fn run(http: &Client, svc: &Service) -> Result<Vec<(FullName, Obs)>> {
         .error_for_status()?
         .json()
         .await?;
-    println!("{}", serde_json::to_string_pretty(&resp)?);
-    Ok(Vec::new())
+    observations(&resp, svc.labeller)
+}
+
+// https://openweathermap.org/api/one-call-3
+// units are "standard": kelvin, m/s, hPa, metres
+#[derive(Deserialize)]
+pub struct OneCall {
+    current: Current,
+    #[serde(default)]
+    minutely: Vec<Minutely>,
+    #[serde(default)]
+    hourly: Vec<Hourly>,
+    #[serde(default)]
+    daily: Vec<Daily>,
+}
+
+#[derive(Deserialize)]
+struct Current {
+    dt: i64,
+    // missing in the polar day/night
+    sunrise: Option<i64>,
+    sunset: Option<i64>,
+    temp: f64,
+    feels_like: f64,
+    pressure: f64,
+    humidity: f64,
+    dew_point: f64,
+    uvi: f64,
+    clouds: f64,
+    visibility: Option<f64>,
+    wind_speed: f64,
+    wind_deg: f64,
+    wind_gust: Option<f64>,
+}
+
+#[derive(Deserialize)]
+struct Minutely {
+    dt: i64,
+    precipitation: f64,
+}
+
+#[derive(Deserialize)]
+struct Hourly {
+    dt: i64,
+    temp: f64,
+    feels_like: f64,
+    pressure: f64,
+    humidity: f64,
+    dew_point: f64,
+    uvi: f64,
+    clouds: f64,
+    visibility: Option<f64>,
+    wind_speed: f64,
+    wind_deg: f64,
+    wind_gust: Option<f64>,
+    pop: f64,
+}
+
+#[derive(Deserialize)]
+struct Daily {
+    dt: i64,
+    sunrise: Option<i64>,
+    sunset: Option<i64>,
+    temp: DailyTemp,
+    feels_like: DailyFeelsLike,
+    pressure: f64,
+    humidity: f64,
+    dew_point: f64,
+    wind_speed: f64,
+    wind_deg: f64,
+    wind_gust: Option<f64>,
+    clouds: f64,
+    pop: f64,
+    uvi: f64,
+}
+
+#[derive(Deserialize)]
+struct DailyTemp {
+    morn: f64,
+    day: f64,
+    eve: f64,
+    night: f64,
+    min: f64,
+    max: f64,
+}
+
+#[derive(Deserialize)]
+struct DailyFeelsLike {
+    morn: f64,
+    day: f64,
+    eve: f64,
+    night: f64,
+}
+
+fn kelvin_to_c(k: f64) -> f64 {
+    k - 273.15
+}
+
+fn from_unix(dt: i64) -> Result<DateTime<Utc>> {
+    Utc.timestamp_opt(dt, 0)
+        .single()
+        .ok_or_else(|| anyhow!("invalid timestamp: {dt}"))
+}
+
+/// Collects metrics for a single response.
+///
+/// Every point carries a `horizon` label: how far ahead of `current` it is, e.g.
+/// `3h` for the third hourly point, `1d` for tomorrow, `15m` for minutely
+/// precipitation, or just `current` for `current` itself, as the nearest hourly point
+/// may also round to `0h`. The observation timestamp is the forecast's target time.
+///
+/// `hourly` also feeds the shared `forecast_*` series, as does `current` if no hourly
+/// point falls in the same hour; both would have the same labels.
+pub fn observations(resp: &OneCall, labeller: Labeller) -> Result<Vec<(FullName, Obs)>> {
+    let mut ret = Vec::with_capacity(1000);
+    let now = resp.current.dt;
+
+    let mut shared = Forecast::new(labeller, "owm", from_unix(now)?);
+    let c = &resp.current;
+    let hour = round_to_hour(from_unix(c.dt)?);
+    let covered = resp
+        .hourly
+        .iter()
+        .any(|h| from_unix(h.dt).is_ok_and(|t| round_to_hour(t) == hour));
+    let current = Some(Point::from(c)).filter(|_| !covered);
+    for h in current
+        .into_iter()
+        .chain(resp.hourly.iter().map(Point::from))
+    {
+        let target = from_unix(h.dt)?;
+        shared.add(target, Quantity::TempC, kelvin_to_c(h.temp))?;
+        shared.add(target, Quantity::CloudCoverPct, h.clouds)?;
+        shared.add(target, Quantity::Uvi, h.uvi)?;
+        shared.add(target, Quantity::WindSpeedMs, h.wind_speed)?;
+        shared.add(target, Quantity::HumidityPct, h.humidity)?;
+        if let Some(pop) = h.pop {
+            shared.add(target, Quantity::PrecipProbPct, pop * 100.)?;
+        }
+    }
+
+    let mut emit = |name: &str, horizon: &str, extra: Option<(&str, &str)>, value, dt| {
+        let mut labels = vec![("horizon", horizon)];
+        labels.extend(extra);
+        ret.push((
+            FullName::new(format!("owm_{name}"), labels)?,
+            Obs::new(value, from_unix(dt)?),
+        ));
+        Ok::<_, anyhow::Error>(())
+    };
+
+    let horizon = "current";
+    emit("temp_c", horizon, None, kelvin_to_c(c.temp), c.dt)?;
+    emit(
+        "feels_like_c",
+        horizon,
+        None,
+        kelvin_to_c(c.feels_like),
+        c.dt,
+    )?;
+    emit("dew_point_c", horizon, None, kelvin_to_c(c.dew_point), c.dt)?;
+    emit("pressure_hpa", horizon, None, c.pressure, c.dt)?;
+    emit("humidity_pct", horizon, None, c.humidity, c.dt)?;
+    emit("uvi", horizon, None, c.uvi, c.dt)?;
+    emit("clouds_pct", horizon, None, c.clouds, c.dt)?;
+    emit("wind_speed_ms", horizon, None, c.wind_speed, c.dt)?;
+    emit("wind_deg", horizon, None, c.wind_deg, c.dt)?;
+    if let Some(gust) = c.wind_gust {
+        emit("wind_gust_ms", horizon, None, gust, c.dt)?;
+    }
+    if let Some(vis) = c.visibility {
+        emit("visibility_m", horizon, None, vis, c.dt)?;
+    }
+    // the value is the (unix) time of the event, so `time() - owm_sunset_ts` works
+    if let Some(sunrise) = c.sunrise {
+        emit("sunrise_ts", horizon, None, sunrise as f64, c.dt)?;
+    }
+    if let Some(sunset) = c.sunset {
+        emit("sunset_ts", horizon, None, sunset as f64, c.dt)?;
+    }
+
+    for m in &resp.minutely {
+        let horizon = format!("{}m", (m.dt - now).div_euclid(60));
+        emit("precipitation_mmh", &horizon, None, m.precipitation, m.dt)?;
+    }
+
+    for h in &resp.hourly {
+        let horizon = format!("{}h", (h.dt - now + 30 * 60).div_euclid(60 * 60));
+        let horizon = horizon.as_str();
+        emit("temp_c", horizon, None, kelvin_to_c(h.temp), h.dt)?;
+        emit(
+            "feels_like_c",
+            horizon,
+            None,
+            kelvin_to_c(h.feels_like),
+            h.dt,
+        )?;
+        emit("dew_point_c", horizon, None, kelvin_to_c(h.dew_point), h.dt)?;
+        emit("pressure_hpa", horizon, None, h.pressure, h.dt)?;
+        emit("humidity_pct", horizon, None, h.humidity, h.dt)?;
+        emit("uvi", horizon, None, h.uvi, h.dt)?;
+        emit("clouds_pct", horizon, None, h.clouds, h.dt)?;
+        emit("wind_speed_ms", horizon, None, h.wind_speed, h.dt)?;
+        emit("wind_deg", horizon, None, h.wind_deg, h.dt)?;
+        if let Some(gust) = h.wind_gust {
+            emit("wind_gust_ms", horizon, None, gust, h.dt)?;
+        }
+        if let Some(vis) = h.visibility {
+            emit("visibility_m", horizon, None, vis, h.dt)?;
+        }
+        emit("pop", horizon, None, h.pop, h.dt)?;
+    }
+
+    let today = from_unix(now)?.date_naive();
+    for d in &resp.daily {
+        let days = (from_unix(d.dt)?.date_naive() - today).num_days();
+        let horizon = format!("{days}d");
+        let horizon = horizon.as_str();
+        for (part, k) in [
+            ("morn", d.temp.morn),
+            ("day", d.temp.day),
+            ("eve", d.temp.eve),
+            ("night", d.temp.night),
+            ("min", d.temp.min),
+            ("max", d.temp.max),
+        ] {
+            emit(
+                "temp_c",
+                horizon,
+                Some(("part", part)),
+                kelvin_to_c(k),
+                d.dt,
+            )?;
+        }
+        for (part, k) in [
+            ("morn", d.feels_like.morn),
+            ("day", d.feels_like.day),
+            ("eve", d.feels_like.eve),
+            ("night", d.feels_like.night),
+        ] {
+            let value = kelvin_to_c(k);
+            emit("feels_like_c", horizon, Some(("part", part)), value, d.dt)?;
+        }
+        emit("dew_point_c", horizon, None, kelvin_to_c(d.dew_point), d.dt)?;
+        emit("pressure_hpa", horizon, None, d.pressure, d.dt)?;
+        emit("humidity_pct", horizon, None, d.humidity, d.dt)?;
+        emit("uvi", horizon, None, d.uvi, d.dt)?;
+        emit("clouds_pct", horizon, None, d.clouds, d.dt)?;
+        emit("wind_speed_ms", horizon, None, d.wind_speed, d.dt)?;
+        emit("wind_deg", horizon, None, d.wind_deg, d.dt)?;
+        if let Some(gust) = d.wind_gust {
+            emit("wind_gust_ms", horizon, None, gust, d.dt)?;
+        }
+        emit("pop", horizon, None, d.pop, d.dt)?;
+        if let Some(sunrise) = d.sunrise {
+            emit("sunrise_ts", horizon, None, sunrise as f64, d.dt)?;
+        }
+        if let Some(sunset) = d.sunset {
+            emit("sunset_ts", horizon, None, sunset as f64, d.dt)?;
+        }
+    }
+
+    ret.extend(shared.finish());
+    Ok(ret)
+}
+
+/// The fields `current` and `hourly` have in common.
+struct Point {
+    dt: i64,
+    temp: f64,
+    clouds: f64,
+    uvi: f64,
+    wind_speed: f64,
+    humidity: f64,
+    pop: Option<f64>,
+}
+
+impl From<&Current> for Point {
+    fn from(c: &Current) -> Self {
+        Point {
+            dt: c.dt,
+            temp: c.temp,
+            clouds: c.clouds,
+            uvi: c.uvi,
+            wind_speed: c.wind_speed,
+            humidity: c.humidity,
+            pop: None,
+        }
+    }
+}
+
+impl From<&Hourly> for Point {
+    fn from(h: &Hourly) -> Self {
+        Point {
+            dt: h.dt,
+            temp: h.temp,
+            clouds: h.clouds,
+            uvi: h.uvi,
+            wind_speed: h.wind_speed,
+            humidity: h.humidity,
+            pop: Some(h.pop),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_one_call() {
+        let resp: OneCall =
+            serde_json::from_str(include_str!("../tests/ref/owm-one-call-3.json")).unwrap();
+        assert_eq!(resp.hourly.len(), 48);
+        assert_eq!(resp.daily.len(), 8);
+        let labeller = Labeller::new(&crate::config::Forecast::default());
+        let obs = observations(&resp, labeller).unwrap();
+        assert!(obs.len() > 48 * 10);
+
+        let find = |name: &str, horizon: &str| {
+            obs.iter()
+                .find(|(n, _)| n.name() == name && n.label("horizon") == Some(horizon))
+                .map(|(_, o)| o.value())
+        };
+        let temp = find("owm_temp_c", "current").unwrap();
+        assert!((temp - 19.02).abs() < 0.001, "{temp}");
+        assert_eq!(find("owm_clouds_pct", "1h"), Some(100.));
+        assert_eq!(find("owm_visibility_m", "current"), Some(10000.));
+        assert_eq!(find("owm_sunset_ts", "current"), Some(1696613035.));
+        assert_eq!(find("owm_clouds_pct", "0h"), Some(resp.hourly[1].clouds));
+        assert_eq!(find("owm_uvi", "0d"), Some(2.6));
+        assert!(find("owm_precipitation_mmh", "0m").is_some());
+
+        // an observation and a forecast mustn't end up as one series
+        let mut seen = std::collections::HashSet::new();
+        for (name, _) in obs.iter().filter(|(n, _)| n.name().starts_with("owm_")) {
+            assert!(seen.insert(name), "{name:?} twice");
+        }
+
+        // current is at 14:42, in the same slot as the 15:00 hourly point, which wins
+        let clouds = obs
+            .iter()
+            .filter(|(n, _)| {
+                n.name() == "forecast_cloud_cover_pct"
+                    && n.label("source") == Some("owm")
+                    && n.label("time") == Some("15:00")
+                    && n.label("period") == Some("0")
+            })
+            .map(|(n, o)| (n.label("advance").unwrap(), o.when()))
+            .collect::<Vec<_>>();
+        assert_eq!(clouds, [("0h", from_unix(resp.hourly[1].dt).unwrap())]);
+    }
 }
@@ -0,0 +1,343 @@
+//! The latest value of every series, served in the Prometheus text format at `/metrics`.
+//!
+//! Forecasts produce many points per series, mostly in the future; only the newest which
+//! isn't is served, and the rest wait until their time comes. A series which hasn't been
+//! updated for a few polls is dropped, so a removed inverter doesn't linger.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, TimeDelta, Utc};
+use log::{info, warn};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::vm::{FullName, Obs};
+
+/// How many of the longest poll interval a series is served for without being updated.
+pub const EXPIRE_POLLS: u32 = 3;
+
+/// Shared between the polling loop and the server.
+#[derive(Clone)]
+pub struct Registry {
+    /// name -> rendered labels -> series
+    metrics: Arc<Mutex<BTreeMap<String, BTreeMap<String, Series>>>>,
+    expire: TimeDelta,
+}
+
+struct Series {
+    /// the newest sample which wasn't in the future when it arrived
+    latest: Option<Obs>,
+    /// samples still to come, which take over from `latest` as their time passes
+    pending: BTreeMap<DateTime<Utc>, Obs>,
+    updated: DateTime<Utc>,
+}
+
+impl Registry {
+    /// `expire` is how long a series is kept after it was last updated.
+    pub fn new(expire: Duration) -> Self {
+        Registry {
+            metrics: Arc::default(),
+            expire: TimeDelta::from_std(expire).unwrap_or(TimeDelta::MAX),
+        }
+    }
+
+    /// Take in what a service produced at `now`.
+    pub fn update(&self, produced: &[(FullName, Obs)], now: DateTime<Utc>) {
+        let mut metrics = self.metrics.lock().expect("not poisoned");
+        for (name, obs) in produced {
+            let series = metrics
+                .entry(name.name().to_string())
+                .or_default()
+                .entry(render_labels(name))
+                .or_insert_with(|| Series {
+                    latest: None,
+                    pending: BTreeMap::new(),
+                    updated: now,
+                });
+            series.updated = now;
+            if obs.when() > now {
+                series.pending.insert(obs.when(), *obs);
+            } else {
+                series.latest = newer(series.latest, *obs);
+            }
+        }
+    }
+
+    /// What a scrape at `now` sees.
+    pub fn render(&self, now: DateTime<Utc>) -> String {
+        let mut metrics = self.metrics.lock().expect("not poisoned");
+        metrics.retain(|_, series| {
+            series.retain(|_, s| now - s.updated <= self.expire);
+            !series.is_empty()
+        });
+
+        let mut out = String::with_capacity(64 * 1024);
+        for (name, series) in metrics.iter_mut() {
+            let mut typed = false;
+            for (labels, s) in series.iter_mut() {
+                while let Some(due) = s.pending.first_entry().filter(|e| *e.key() <= now) {
+                    s.latest = newer(s.latest, due.remove());
+                }
+                let Some(obs) = s.latest else {
+                    continue;
+                };
+                if !typed {
+                    let _ = writeln!(out, "# HELP {name} {}", help(name));
+                    let _ = writeln!(out, "# TYPE {name} gauge");
+                    typed = true;
+                }
+                let _ = writeln!(
+                    out,
+                    "{name}{labels} {} {}",
+                    render_value(obs.value()),
+                    obs.when().timestamp_millis()
+                );
+            }
+        }
+        out
+    }
+}
+
+/// Where a series comes from, by its name's prefix.
+const SOURCES: &[(&str, &str)] = &[
+    ("soliscloud_", "SolisCloud"),
+    ("met_", "Met Office"),
+    ("owm_", "OpenWeatherMap"),
+    ("sun_", "sun position"),
+    ("pv_forecast_", "PV forecast from the panels"),
+    ("score_", "forecast score"),
+    ("forecast_", "weather forecast"),
+];
+
+/// What a series is measured in, by its name's suffix.
+const UNITS: &[(&str, &str)] = &[
+    ("_kwh", "kilowatt-hours"),
+    ("_kwp", "kilowatts peak"),
+    ("_w", "watts"),
+    ("_va", "volt-amperes"),
+    ("_var", "volt-amperes reactive"),
+    ("_v", "volts"),
+    ("_a", "amperes"),
+    ("_c", "degrees Celsius"),
+    ("_pct", "percent"),
+    ("_hpa", "hectopascals"),
+    ("_ms", "metres per second"),
+    ("_mph", "miles per hour"),
+    ("_mmh", "millimetres per hour"),
+    ("_km", "kilometres"),
+    ("_m", "metres"),
+    ("_deg", "degrees"),
+    ("_ts", "seconds since the Unix epoch"),
+];
+
+/// A description for `# HELP`, spelt out from the naming conventions, e.g.
+/// `soliscloud_ac_power_w` is "SolisCloud: ac power, in watts".
+fn help(name: &str) -> String {
+    let (stem, unit) = UNITS
+        .iter()
+        .find_map(|(suffix, unit)| Some((name.strip_suffix(suffix)?, Some(unit))))
+        .unwrap_or((name, None));
+    let (source, what) = SOURCES
+        .iter()
+        .find_map(|(prefix, source)| Some((Some(source), stem.strip_prefix(prefix)?)))
+        .unwrap_or((None, stem));
+    let mut out = what.replace('_', " ");
+    if let Some(source) = source {
+        out = format!("{source}: {out}");
+    }
+    if let Some(unit) = unit {
+        let _ = write!(out, ", in {unit}");
+    }
+    out
+}
+
+fn newer(old: Option<Obs>, obs: Obs) -> Option<Obs> {
+    match old {
+        Some(old) if old.when() > obs.when() => Some(old),
+        _ => Some(obs),
+    }
+}
+
+fn render_labels(name: &FullName) -> String {
+    let mut out = String::new();
+    for (k, v) in name.labels() {
+        out.push(if out.is_empty() { '{' } else { ',' });
+        let v = v
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n");
+        let _ = write!(out, "{k}=\"{v}\"");
+    }
+    if !out.is_empty() {
+        out.push('}');
+    }
+    out
+}
+
+fn render_value(v: f64) -> String {
+    if v.is_nan() {
+        "NaN".to_string()
+    } else if v.is_infinite() {
+        if v > 0. { "+Inf" } else { "-Inf" }.to_string()
+    } else {
+        v.to_string()
+    }
+}
+
+pub async fn bind(addr: SocketAddr) -> Result<TcpListener> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("listening on {addr}"))?;
+    info!("serving metrics on http://{addr}/metrics");
+    Ok(listener)
+}
+
+/// Answer scrapes forever.
+pub async fn serve(listener: TcpListener, registry: Registry) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                warn!("accepting scrape: {e:?}");
+                continue;
+            }
+        };
+        let registry = registry.clone();
+        tokio::spawn(async move {
+            if let Err(e) = answer(stream, &registry).await {
+                warn!("answering scrape: {e:?}");
+            }
+        });
+    }
+}
+
+/// One request per connection; nothing but `GET /metrics` is expected.
+async fn answer(mut stream: TcpStream, registry: &Registry) -> Result<()> {
+    let mut head = Vec::with_capacity(1024);
+    let mut buf = [0; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 || head.len() > 16 * 1024 {
+            return Ok(());
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    let head = String::from_utf8_lossy(&head);
+    let mut request = head.split_whitespace();
+    let (status, body) = match (request.next(), request.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", registry.render(Utc::now())),
+        _ => ("404 Not Found", String::new()),
+    };
+    let reply = format!(
+        "HTTP/1.1 {status}\r\n\
+         content-type: text/plain; version=0.0.4; charset=utf-8\r\n\
+         content-length: {}\r\n\
+         connection: close\r\n\r\n",
+        body.len()
+    );
+    stream.write_all(reply.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use chrono::{TimeDelta, Utc};
+
+    use super::*;
+
+    #[test]
+    fn test_render() -> Result<()> {
+        let now = Utc::now();
+        let registry = Registry::new(Duration::from_secs(60));
+        let power = || FullName::new("ac_power_w", [("site", "home"), ("id", "1\"")]).unwrap();
+        registry.update(
+            &[
+                (power(), Obs::new(1., now - TimeDelta::minutes(5))),
+                (power(), Obs::new(2., now)),
+                (power(), Obs::new(3., now - TimeDelta::minutes(1))),
+                (
+                    FullName::plain("forecast_w")?,
+                    Obs::new(5., now + TimeDelta::hours(2)),
+                ),
+                (
+                    FullName::plain("forecast_w")?,
+                    Obs::new(4., now + TimeDelta::seconds(10)),
+                ),
+            ],
+            now,
+        );
+
+        let power = format!(
+            "# HELP ac_power_w ac power, in watts\n\
+             # TYPE ac_power_w gauge\n\
+             ac_power_w{{id=\"1\\\"\",site=\"home\"}} 2 {}\n",
+            now.timestamp_millis()
+        );
+        assert_eq!(registry.render(now), power);
+
+        // the nearer forecast comes due, the other is still in the future
+        assert_eq!(
+            registry.render(now + TimeDelta::seconds(30)),
+            format!(
+                "{power}# HELP forecast_w forecast, in watts\n\
+                 # TYPE forecast_w gauge\n\
+                 forecast_w 4 {}\n",
+                (now + TimeDelta::seconds(10)).timestamp_millis()
+            )
+        );
+
+        // nothing's been updated since
+        assert_eq!(registry.render(now + TimeDelta::seconds(61)), "");
+        Ok(())
+    }
+
+    #[test]
+    fn test_help() {
+        assert_eq!(
+            help("soliscloud_ac_power_w"),
+            "SolisCloud: ac power, in watts"
+        );
+        assert_eq!(
+            help("soliscloud_battery_today_charge_energy_kwh"),
+            "SolisCloud: battery today charge energy, in kilowatt-hours"
+        );
+        assert_eq!(
+            help("pv_forecast_power_w"),
+            "PV forecast from the panels: power, in watts"
+        );
+        assert_eq!(help("owm_uvi"), "OpenWeatherMap: uvi");
+        assert_eq!(help("battery_soc"), "battery soc");
+    }
+
+    #[tokio::test]
+    async fn test_serve() -> Result<()> {
+        let registry = Registry::new(Duration::from_secs(3600));
+        registry.update(
+            &[(FullName::plain("ac_power_w")?, Obs::new(2., Utc::now()))],
+            Utc::now(),
+        );
+
+        let listener = bind("127.0.0.1:0".parse()?).await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(serve(listener, registry));
+
+        let scraped = reqwest::get(format!("http://{addr}/metrics"))
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        assert!(scraped.contains("\nac_power_w 2 "), "{scraped}");
+
+        let missing = reqwest::get(format!("http://{addr}/")).await?;
+        assert_eq!(missing.status(), 404);
+        Ok(())
+    }
+}
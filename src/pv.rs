@@ -0,0 +1,268 @@
+//! Turns cloud cover forecasts into a guess at what the panels will produce.
+//!
+//! For every `forecast_cloud_cover_pct` point, the clear-sky irradiance on the panels
+//! is reduced by the cloud cover (Kasten & Czeplak), giving `pv_forecast_power_w` with
+//! the same labels as the cloud cover. The points from each source are also integrated
+//! into `pv_forecast_energy_kwh` for each `period` whose daylight they cover completely;
+//! a period which is already under way, or runs off the end of the forecast, gets none.
+//!
+//! UV index forecasts aren't used: they follow from the sun's height and the cloud, which
+//! are both accounted for already.
+
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use chrono::{DateTime, TimeDelta, Utc};
+
+use crate::config::Loc;
+use crate::forecast::Quantity;
+use crate::solar;
+use crate::vm::{FullName, Obs};
+
+/// Inverter, wiring, dirt, temperature, ..
+const SYSTEM_EFFICIENCY: f64 = 0.85;
+
+/// Share of clear-sky horizontal irradiance that comes straight from the sun.
+const BEAM_FRACTION: f64 = 0.85;
+
+/// Points further apart than this (e.g. the end of a 3-hourly forecast) don't fill the gap.
+const MAX_STEP: TimeDelta = TimeDelta::hours(3);
+
+/// Integration step for the energy total.
+const SLICE: TimeDelta = TimeDelta::minutes(15);
+
+/// The time one source's points for a `period` speak for, and what they add up to.
+struct Covered {
+    kwh: f64,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    /// daylight between two points which neither speaks for
+    gap: bool,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Panels {
+    lat: f64,
+    lon: f64,
+    tilt_deg: f64,
+    az_deg: f64,
+    kwp: f64,
+}
+
+impl Panels {
+    /// `None` unless the location has all of the panel geometry.
+    pub fn from_loc(loc: &Loc) -> Option<Self> {
+        Some(Panels {
+            lat: loc.lat,
+            lon: loc.lon,
+            tilt_deg: loc.dec_deg?,
+            az_deg: loc.az_deg?,
+            kwp: loc.kwp?,
+        })
+    }
+
+    /// Expected AC output at `when`, given the cloud cover.
+    pub fn power_w(&self, when: DateTime<Utc>, cloud_cover_pct: f64) -> f64 {
+        let sun = solar::position(self.lat, self.lon, when);
+        let ghi = solar::clear_sky_ghi(&sun);
+        if ghi <= 0. {
+            return 0.;
+        }
+
+        let zenith = sun.zenith_deg().to_radians();
+        let tilt = self.tilt_deg.to_radians();
+        let cos_incidence = zenith.cos() * tilt.cos()
+            + zenith.sin() * tilt.sin() * (sun.azimuth_deg - self.az_deg).to_radians().cos();
+
+        let dni = ghi * BEAM_FRACTION / zenith.cos();
+        let dhi = ghi * (1. - BEAM_FRACTION);
+        let plane = dni * cos_incidence.max(0.) + dhi * (1. + tilt.cos()) / 2.;
+
+        let cloud = (cloud_cover_pct / 100.).clamp(0., 1.);
+        let attenuation = 1. - 0.75 * cloud.powf(3.4);
+
+        self.kwp * plane * attenuation * SYSTEM_EFFICIENCY
+    }
+
+    /// Whether the sun is up at any point from `from` to `to`.
+    fn lit(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> bool {
+        let mut t = from;
+        while t < to {
+            if self.power_w(t, 0.) > 0. {
+                return true;
+            }
+            t += SLICE;
+        }
+        self.power_w(to, 0.) > 0.
+    }
+
+    /// Power and energy forecasts for any cloud cover forecasts in `produced`.
+    pub fn forecast(
+        &self,
+        produced: &[(FullName, Obs)],
+        now: DateTime<Utc>,
+    ) -> Result<Vec<(FullName, Obs)>> {
+        let mut ret = Vec::new();
+
+        // source -> target -> (period, cloud cover); the first opinion on a target wins
+        let mut by_source = BTreeMap::<&str, BTreeMap<DateTime<Utc>, (&str, f64)>>::new();
+
+        for (name, obs) in produced {
+            if name.name() != Quantity::CloudCoverPct.metric_name() {
+                continue;
+            }
+            let (Some(source), Some(period)) = (name.label("source"), name.label("period")) else {
+                continue;
+            };
+            let labels = ["source", "period", "time", "advance"]
+                .into_iter()
+                .filter_map(|k| Some((k, name.label(k)?)));
+            ret.push((
+                FullName::new("pv_forecast_power_w", labels)?,
+                Obs::new(self.power_w(obs.when(), obs.value()), obs.when()),
+            ));
+            by_source
+                .entry(source)
+                .or_default()
+                .entry(obs.when())
+                .or_insert((period, obs.value()));
+        }
+
+        for (source, points) in by_source {
+            let mut energy = BTreeMap::<&str, Covered>::new();
+            let times = points.keys().copied().collect::<Vec<_>>();
+            for (i, (when, (period, cloud))) in points.iter().enumerate() {
+                // each point speaks for the time until halfway to its neighbours
+                let before = i
+                    .checked_sub(1)
+                    .map(|p| (*when - times[p]).min(MAX_STEP))
+                    .unwrap_or(TimeDelta::hours(1));
+                let after = times
+                    .get(i + 1)
+                    .map(|n| (*n - *when).min(MAX_STEP))
+                    .unwrap_or(before);
+                let start = *when - before / 2;
+                let end = *when + after / 2;
+                let mut t = start;
+                let mut wh = 0.;
+                while t < end {
+                    let step = SLICE.min(end - t);
+                    let mid = t + step / 2;
+                    wh += self.power_w(mid, *cloud) * step.num_seconds() as f64 / 3600.;
+                    t += step;
+                }
+                let covered = energy.entry(period).or_insert(Covered {
+                    kwh: 0.,
+                    start,
+                    end: start,
+                    gap: false,
+                });
+                covered.gap |= start > covered.end && self.lit(covered.end, start);
+                covered.kwh += wh / 1000.;
+                covered.end = end;
+            }
+            for (period, covered) in energy {
+                // daylight is one stretch in a period, so dark at both ends means all of it
+                let complete = !covered.gap
+                    && covered.kwh > 0.
+                    && self.power_w(covered.start, 0.) <= 0.
+                    && self.power_w(covered.end, 0.) <= 0.;
+                if !complete {
+                    continue;
+                }
+                ret.push((
+                    FullName::new(
+                        "pv_forecast_energy_kwh",
+                        [("source", source), ("period", period)],
+                    )?,
+                    Obs::new(covered.kwh, now),
+                ));
+            }
+        }
+
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, TimeZone, Utc};
+
+    use super::*;
+
+    fn panels() -> Panels {
+        Panels::from_loc(&Loc {
+            lat: 51.5,
+            lon: 0.,
+            dec_deg: Some(35.),
+            az_deg: Some(180.),
+            kwp: Some(3.),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_power() {
+        let p = panels();
+        let noon = Utc.with_ymd_and_hms(2023, 6, 21, 12, 0, 0).unwrap();
+        let clear = p.power_w(noon, 0.);
+        assert!(clear > 2000. && clear < 3000., "{clear}");
+        assert!(p.power_w(noon, 100.) < clear / 3.);
+        let midnight = Utc.with_ymd_and_hms(2023, 6, 21, 0, 0, 0).unwrap();
+        assert_eq!(p.power_w(midnight, 0.), 0.);
+    }
+
+    fn clear(now: DateTime<Utc>, hours: impl Iterator<Item = i64>) -> Vec<(FullName, Obs)> {
+        hours
+            .map(|h| {
+                (
+                    FullName::new(
+                        Quantity::CloudCoverPct.metric_name(),
+                        [
+                            ("source", "owm"),
+                            ("period", "0"),
+                            ("time", "whatever"),
+                            ("advance", "0h"),
+                        ],
+                    )
+                    .unwrap(),
+                    Obs::new(0., now + TimeDelta::hours(h)),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_forecast() {
+        let p = panels();
+        let now = Utc.with_ymd_and_hms(2023, 6, 21, 0, 0, 0).unwrap();
+        let out = p.forecast(&clear(now, 0..24), now).unwrap();
+        assert_eq!(
+            out.iter()
+                .filter(|(n, _)| n.name() == "pv_forecast_power_w")
+                .count(),
+            24
+        );
+        let (_, kwh) = out
+            .iter()
+            .find(|(n, _)| n.name() == "pv_forecast_energy_kwh")
+            .unwrap();
+        // a clear midsummer day for 3kWp in London
+        assert!(kwh.value() > 15. && kwh.value() < 25., "{kwh:?}");
+
+        // starting after sunrise, or missing the middle of the day, isn't a whole day
+        let energy = |produced: Vec<_>| {
+            p.forecast(&produced, now)
+                .unwrap()
+                .into_iter()
+                .filter(|(n, _)| n.name() == "pv_forecast_energy_kwh")
+                .count()
+        };
+        assert_eq!(energy(clear(now, 8..24)), 0);
+        assert_eq!(
+            energy(clear(now, (0..24).filter(|h| !(9..16).contains(h)))),
+            0
+        );
+        assert_eq!(energy(clear(now, 0..16)), 0);
+    }
+}
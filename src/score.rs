@@ -0,0 +1,396 @@
+//! Scores cloud cover forecasts against what the panels actually saw.
+//!
+//! Every `forecast_cloud_cover_pct` point and every generation sample passing through
+//! is remembered in a small state file. Once an hour is over, the mean generation
+//! for that hour is turned into an "observed" cloud cover, by comparing it with what
+//! the panels would have made under a clear sky, and each remembered forecast for the
+//! hour is scored against that.
+//!
+//! The clear-sky expectation comes from the panel geometry if the site has it.
+//! Otherwise it's the best generation seen for that time of day recently, which is
+//! crude, and means nothing until there's been a week or so of it.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, TimeDelta, TimeZone, Timelike, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+use crate::forecast::{round_to_hour, Quantity};
+use crate::pv::Panels;
+use crate::vm::{FullName, Obs};
+
+const GENERATION_METRIC: &str = "soliscloud_ac_power_w";
+
+/// Below this, it's night (or the panels are under snow), and there's nothing to score.
+const MIN_CLEAR_SKY_W: f64 = 50.;
+
+/// How long the best-seen generation for an hour of day is trusted for; the sun moves.
+const CLEAR_SKY_MEMORY_DAYS: i64 = 30;
+
+/// How many earlier days of generation an hour of day needs before the best of them is
+/// any guide to a clear sky.
+const CLEAR_SKY_MIN_DAYS: usize = 7;
+
+/// How long to keep forecasts or generation around waiting for the other half.
+const RETENTION_DAYS: i64 = 3;
+
+pub struct Scorer {
+    path: PathBuf,
+    /// keyed by site name; a site without panel geometry learns its clear sky instead
+    panels: BTreeMap<String, Panels>,
+    state: State,
+    /// whether `state` has changed since it was last saved
+    dirty: bool,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct State {
+    /// keyed by the `site` label
+    sites: BTreeMap<String, SiteState>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct SiteState {
+    /// keyed by the target hour, unix seconds
+    forecasts: BTreeMap<i64, Vec<Prediction>>,
+    /// keyed by the hour, unix seconds, then inverter id
+    generation: BTreeMap<i64, BTreeMap<String, Mean>>,
+    /// keyed by hour of day (UTC), then the hour, unix seconds: the generation then
+    #[serde(default)]
+    clear_sky_w: BTreeMap<u32, BTreeMap<i64, f64>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Prediction {
+    source: String,
+    advance: i64,
+    cloud_cover_pct: f64,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Mean {
+    sum: f64,
+    count: u32,
+}
+
+impl Mean {
+    fn get(&self) -> f64 {
+        self.sum / f64::from(self.count)
+    }
+}
+
+impl Scorer {
+    pub fn load(config: &config::Score, panels: BTreeMap<String, Panels>) -> Result<Self> {
+        let path = config.state.clone();
+        let state = match fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .with_context(|| anyhow!("parsing score state {path:?}"))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => State::default(),
+            Err(e) => return Err(e).with_context(|| anyhow!("reading score state {path:?}")),
+        };
+        Ok(Scorer {
+            path,
+            panels,
+            state,
+            dirty: false,
+        })
+    }
+
+    /// Remember anything interesting in `produced`, and score any hours which are now over.
+    pub fn observe(
+        &mut self,
+        produced: &[(FullName, Obs)],
+        now: DateTime<Utc>,
+    ) -> Result<Vec<(FullName, Obs)>> {
+        for (name, obs) in produced {
+            let site = name.label("site").unwrap_or_default();
+            if name.name() == Quantity::CloudCoverPct.metric_name() {
+                let site = self.state.sites.entry(site.to_string()).or_default();
+                self.dirty |= site.remember_forecast(name, obs)?;
+            } else if name.name() == GENERATION_METRIC {
+                let hour = round_to_hour(obs.when()).timestamp();
+                let id = name.label("id").unwrap_or_default().to_string();
+                let mean = self
+                    .state
+                    .sites
+                    .entry(site.to_string())
+                    .or_default()
+                    .generation
+                    .entry(hour)
+                    .or_default()
+                    .entry(id)
+                    .or_default();
+                mean.sum += obs.value();
+                mean.count += 1;
+                self.dirty = true;
+            }
+        }
+
+        let mut ret = Vec::new();
+        for (site, state) in &mut self.state.sites {
+            // scoring only ever takes hours away
+            let before = (state.forecasts.len(), state.generation.len());
+            for (mut name, obs) in state.score(self.panels.get(site), now)? {
+                name.add_label("site", site)?;
+                ret.push((name, obs));
+            }
+            self.dirty |= before != (state.forecasts.len(), state.generation.len());
+        }
+        if self.dirty {
+            self.save()?;
+            self.dirty = false;
+        }
+        Ok(ret)
+    }
+
+    fn save(&self) -> Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(&self.state)?)
+            .with_context(|| anyhow!("writing score state {tmp:?}"))?;
+        fs::rename(&tmp, &self.path).with_context(|| anyhow!("replacing {:?}", self.path))?;
+        Ok(())
+    }
+}
+
+impl SiteState {
+    /// Whether it was worth remembering.
+    fn remember_forecast(&mut self, name: &FullName, obs: &Obs) -> Result<bool> {
+        let source = name
+            .label("source")
+            .ok_or_else(|| anyhow!("forecast without source"))?;
+        let advance = name
+            .label("advance")
+            .and_then(|a| a.strip_suffix('h'))
+            .ok_or_else(|| anyhow!("forecast without advance"))?
+            .parse::<i64>()?;
+        // "forecasts" of the past aren't interesting
+        if advance < 0 {
+            return Ok(false);
+        }
+        let predictions = self.forecasts.entry(obs.when().timestamp()).or_default();
+        predictions.retain(|p| !(p.source == source && p.advance == advance));
+        predictions.push(Prediction {
+            source: source.to_string(),
+            advance,
+            cloud_cover_pct: obs.value(),
+        });
+        Ok(true)
+    }
+
+    fn score(
+        &mut self,
+        panels: Option<&Panels>,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<(FullName, Obs)>> {
+        let mut ret = Vec::new();
+        // an hour's bucket covers half an hour either side of it
+        let finished = (now - TimeDelta::minutes(30)).timestamp();
+        let hours = self
+            .generation
+            .range(..finished)
+            .map(|(hour, _)| *hour)
+            .collect::<Vec<_>>();
+
+        for hour in hours {
+            let generation = self
+                .generation
+                .remove(&hour)
+                .expect("just listed")
+                .values()
+                .map(Mean::get)
+                .sum::<f64>();
+            let when = Utc
+                .timestamp_opt(hour, 0)
+                .single()
+                .ok_or_else(|| anyhow!("invalid hour {hour}"))?;
+
+            let clear_sky_w = match panels {
+                Some(panels) => Some(panels.power_w(when, 0.)),
+                None => self.learn_clear_sky(when, generation),
+            };
+
+            let Some(predictions) = self.forecasts.remove(&hour) else {
+                continue;
+            };
+
+            let Some(clear_sky_w) = clear_sky_w.filter(|w| *w >= MIN_CLEAR_SKY_W) else {
+                continue;
+            };
+
+            let observed = 100. * (1. - generation / clear_sky_w).clamp(0., 1.);
+            ret.push((
+                FullName::new("score_observed_cloud_cover_pct", [("source", "soliscloud")])?,
+                Obs::new(observed, when),
+            ));
+            for p in predictions {
+                ret.push((
+                    FullName::new(
+                        "score_cloud_cover_error_pct",
+                        [("source", p.source), ("advance", format!("{}h", p.advance))],
+                    )?,
+                    Obs::new(p.cloud_cover_pct - observed, when),
+                ));
+            }
+        }
+
+        let expired = (now - TimeDelta::days(RETENTION_DAYS)).timestamp();
+        self.forecasts = self.forecasts.split_off(&expired);
+        self.generation = self.generation.split_off(&expired);
+
+        Ok(ret)
+    }
+
+    /// The best generation seen at this time of day recently, if there's enough history
+    /// for it to mean anything.
+    fn learn_clear_sky(&mut self, when: DateTime<Utc>, generation: f64) -> Option<f64> {
+        let seen = self.clear_sky_w.entry(when.hour()).or_default();
+        let since = when - TimeDelta::days(CLEAR_SKY_MEMORY_DAYS);
+        *seen = seen.split_off(&since.timestamp());
+        let best = (seen.len() >= CLEAR_SKY_MIN_DAYS)
+            .then(|| seen.values().fold(generation, |best, w| best.max(*w)));
+        seen.insert(when.timestamp(), generation);
+        best
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeDelta, TimeZone, Utc};
+
+    use super::*;
+
+    fn generation(w: f64, when: DateTime<Utc>) -> (FullName, Obs) {
+        (
+            FullName::new(GENERATION_METRIC, [("id", "1"), ("site", "home")]).unwrap(),
+            Obs::new(w, when),
+        )
+    }
+
+    fn cloud(source: &str, advance: &str, pct: f64, when: DateTime<Utc>) -> (FullName, Obs) {
+        (
+            FullName::new(
+                Quantity::CloudCoverPct.metric_name(),
+                [("source", source), ("advance", advance), ("site", "home")],
+            )
+            .unwrap(),
+            Obs::new(pct, when),
+        )
+    }
+
+    #[test]
+    fn test_score() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("disport-score-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut scorer = Scorer::load(
+            &config::Score {
+                state: path.clone(),
+            },
+            BTreeMap::new(),
+        )?;
+
+        // a sunny week, to learn what clear sky looks like; until then, nothing is scored
+        let sunny = Utc.with_ymd_and_hms(2023, 9, 28, 12, 0, 0).unwrap();
+        for day in 0..CLEAR_SKY_MIN_DAYS as i64 {
+            let when = sunny + TimeDelta::days(day);
+            let out = scorer.observe(
+                &[cloud("met", "3h", 0., when), generation(2000., when)],
+                when + TimeDelta::hours(1),
+            )?;
+            assert!(out.is_empty(), "{out:?}");
+        }
+
+        let noon = sunny + TimeDelta::days(CLEAR_SKY_MIN_DAYS as i64);
+        let out = scorer.observe(
+            &[
+                cloud("met", "3h", 80., noon),
+                cloud("owm", "3h", 40., noon),
+                cloud("owm", "-1h", 0., noon),
+                generation(900., noon - TimeDelta::minutes(10)),
+                generation(1100., noon + TimeDelta::minutes(10)),
+            ],
+            noon,
+        )?;
+        assert!(out.is_empty(), "hour isn't over yet");
+
+        // the state survives a restart
+        drop(scorer);
+        let mut scorer = Scorer::load(
+            &config::Score {
+                state: path.clone(),
+            },
+            BTreeMap::new(),
+        )?;
+        let out = scorer.observe(&[], noon + TimeDelta::hours(1))?;
+        let get = |name: &str, source: Option<&str>| {
+            out.iter()
+                .find(|(n, _)| n.name() == name && n.label("source") == source)
+                .map(|(_, o)| o.value())
+        };
+        assert_eq!(
+            get("score_observed_cloud_cover_pct", Some("soliscloud")),
+            Some(50.)
+        );
+        assert_eq!(get("score_cloud_cover_error_pct", Some("met")), Some(30.));
+        assert_eq!(get("score_cloud_cover_error_pct", Some("owm")), Some(-10.));
+        assert_eq!(out.len(), 3);
+        assert!(out.iter().all(|(n, _)| n.label("site") == Some("home")));
+
+        // nothing new, so nothing to save
+        fs::remove_file(&path)?;
+        scorer.observe(&[], noon + TimeDelta::hours(2))?;
+        assert!(!path.exists());
+        scorer.observe(&[cloud("met", "-1h", 0., noon)], noon + TimeDelta::hours(2))?;
+        assert!(!path.exists());
+        scorer.observe(&[generation(0., noon)], noon + TimeDelta::hours(2))?;
+        assert!(path.exists());
+
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_score_panels() -> Result<()> {
+        let path =
+            std::env::temp_dir().join(format!("disport-score-panels-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let panels = Panels::from_loc(&config::Loc {
+            lat: 51.5,
+            lon: -0.1,
+            dec_deg: Some(35.),
+            az_deg: Some(180.),
+            kwp: Some(4.),
+        })
+        .expect("geometry");
+        let mut scorer = Scorer::load(
+            &config::Score {
+                state: path.clone(),
+            },
+            [("home".to_string(), panels)].into(),
+        )?;
+
+        // no history needed: the geometry says what a clear sky gives
+        let noon = Utc.with_ymd_and_hms(2023, 6, 21, 12, 0, 0).unwrap();
+        let clear_sky_w = panels.power_w(noon, 0.);
+        let out = scorer.observe(
+            &[
+                cloud("owm", "3h", 40., noon),
+                generation(clear_sky_w / 4., noon),
+            ],
+            noon + TimeDelta::hours(1),
+        )?;
+        let observed = out
+            .iter()
+            .find(|(n, _)| n.name() == "score_observed_cloud_cover_pct")
+            .map(|(_, o)| o.value());
+        assert_eq!(observed, Some(75.));
+        assert_eq!(out.len(), 2);
+
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,206 @@
+//! Where the sun is, and how much light that'd give on a clear day; no network involved.
+//!
+//! Low precision (a fraction of a degree), which is plenty for guessing at solar panels.
+
+use anyhow::Result;
+use chrono::{DateTime, NaiveDate, TimeDelta, Utc};
+
+use crate::config::Loc;
+use crate::vm::{FullName, Obs};
+
+/// Standard refraction and solar disc allowance for rise and set.
+const HORIZON_DEG: f64 = -0.833;
+
+pub struct Service {
+    pub loc: Loc,
+}
+
+/// Where the sun is now, and when it rises and sets today (UTC).
+pub fn run(svc: &Service, now: DateTime<Utc>) -> Result<Vec<(FullName, Obs)>> {
+    let Loc { lat, lon, .. } = svc.loc;
+    let pos = position(lat, lon, now);
+    let mut ret = vec![
+        (
+            FullName::plain("sun_elevation_deg")?,
+            Obs::new(pos.elevation_deg, now),
+        ),
+        (
+            FullName::plain("sun_azimuth_deg")?,
+            Obs::new(pos.azimuth_deg, now),
+        ),
+        (
+            FullName::plain("sun_clear_sky_ghi_wm2")?,
+            Obs::new(clear_sky_ghi(&pos), now),
+        ),
+    ];
+
+    let times = sun_times(lat, lon, now.date_naive());
+    // the value is the (unix) time of the event, like `owm_sunrise_ts`
+    for (name, when) in [
+        ("sun_sunrise_ts", times.sunrise),
+        ("sun_sunset_ts", times.sunset),
+    ] {
+        if let Some(when) = when {
+            ret.push((
+                FullName::plain(name)?,
+                Obs::new(when.timestamp() as f64, now),
+            ));
+        }
+    }
+    Ok(ret)
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Position {
+    /// degrees above the horizon; negative at night
+    pub elevation_deg: f64,
+    /// degrees clockwise from north
+    pub azimuth_deg: f64,
+}
+
+impl Position {
+    pub fn zenith_deg(&self) -> f64 {
+        90. - self.elevation_deg
+    }
+}
+
+// https://en.wikipedia.org/wiki/Position_of_the_Sun
+pub fn position(lat: f64, lon: f64, when: DateTime<Utc>) -> Position {
+    let (dec, ra) = declination_right_ascension(when);
+
+    let gmst_hours = 18.697374558 + 24.06570982441908 * days_since_j2000(when);
+    let hour_angle = (gmst_hours * 15. + lon).to_radians() - ra;
+
+    let lat = lat.to_radians();
+    let elevation = (lat.sin() * dec.sin() + lat.cos() * dec.cos() * hour_angle.cos()).asin();
+    let azimuth = (-hour_angle.sin()).atan2(dec.tan() * lat.cos() - lat.sin() * hour_angle.cos());
+
+    Position {
+        elevation_deg: elevation.to_degrees(),
+        azimuth_deg: azimuth.to_degrees().rem_euclid(360.),
+    }
+}
+
+/// Global horizontal irradiance under a clear sky, in W/m², by the Haurwitz model.
+pub fn clear_sky_ghi(pos: &Position) -> f64 {
+    let cos_zenith = pos.zenith_deg().to_radians().cos();
+    if cos_zenith <= 0. {
+        return 0.;
+    }
+    1098. * cos_zenith * (-0.057 / cos_zenith).exp()
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct SunTimes {
+    /// `None` if the sun doesn't rise (or set) on this day, e.g. in the polar summer
+    pub sunrise: Option<DateTime<Utc>>,
+    pub sunset: Option<DateTime<Utc>>,
+}
+
+/// Sunrise and sunset around the solar noon nearest to midday UTC on `date`.
+pub fn sun_times(lat: f64, lon: f64, date: NaiveDate) -> SunTimes {
+    let approx_noon = date.and_hms_opt(12, 0, 0).expect("valid time").and_utc()
+        - TimeDelta::seconds((lon / 15. * 3600.) as i64);
+    let above = |when| position(lat, lon, when).elevation_deg > HORIZON_DEG;
+
+    // walk outwards from noon until the sun is on the other side of the horizon
+    let step = TimeDelta::minutes(10);
+    let crossing = |dir: i32| {
+        let mut prev = approx_noon;
+        while prev - approx_noon < TimeDelta::hours(13) && approx_noon - prev < TimeDelta::hours(13)
+        {
+            let next = prev + step * dir;
+            if above(prev) != above(next) {
+                return Some(bisect(prev, next, &above));
+            }
+            prev = next;
+        }
+        None
+    };
+
+    SunTimes {
+        sunrise: crossing(-1),
+        sunset: crossing(1),
+    }
+}
+
+fn bisect(
+    mut a: DateTime<Utc>,
+    mut b: DateTime<Utc>,
+    above: &impl Fn(DateTime<Utc>) -> bool,
+) -> DateTime<Utc> {
+    let side = above(a);
+    while (b - a).abs() > TimeDelta::seconds(1) {
+        let mid = a + (b - a) / 2;
+        if above(mid) == side {
+            a = mid;
+        } else {
+            b = mid;
+        }
+    }
+    a
+}
+
+fn days_since_j2000(when: DateTime<Utc>) -> f64 {
+    when.timestamp_millis() as f64 / 86_400_000. - 10_957.5
+}
+
+/// Both in radians.
+fn declination_right_ascension(when: DateTime<Utc>) -> (f64, f64) {
+    let d = days_since_j2000(when);
+    let mean_anomaly = (357.529 + 0.98560028 * d).to_radians();
+    let mean_longitude = 280.459 + 0.98564736 * d;
+    let ecliptic_longitude =
+        (mean_longitude + 1.915 * mean_anomaly.sin() + 0.020 * (2. * mean_anomaly).sin())
+            .to_radians();
+    let obliquity = (23.439 - 0.00000036 * d).to_radians();
+
+    let dec = (obliquity.sin() * ecliptic_longitude.sin()).asin();
+    let ra = (obliquity.cos() * ecliptic_longitude.sin()).atan2(ecliptic_longitude.cos());
+    (dec, ra)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+
+    #[test]
+    fn test_position() {
+        // London, around solar noon on the equinox
+        let when = Utc.with_ymd_and_hms(2023, 3, 20, 12, 7, 0).unwrap();
+        let pos = position(51.5, 0., when);
+        assert!((pos.elevation_deg - 38.5).abs() < 0.5, "{pos:?}");
+        assert!((pos.azimuth_deg - 180.).abs() < 1., "{pos:?}");
+
+        let night = Utc.with_ymd_and_hms(2023, 3, 20, 0, 7, 0).unwrap();
+        let pos = position(51.5, 0., night);
+        assert!(pos.elevation_deg < -30., "{pos:?}");
+        assert_eq!(clear_sky_ghi(&pos), 0.);
+    }
+
+    #[test]
+    fn test_sun_times() {
+        // London, 2023-10-06: sunrise 06:08 UTC, sunset 17:28 UTC
+        let date = NaiveDate::from_ymd_opt(2023, 10, 6).unwrap();
+        let times = sun_times(51.5, -0.12, date);
+        let sunrise = times.sunrise.unwrap();
+        let sunset = times.sunset.unwrap();
+        let expected = Utc.with_ymd_and_hms(2023, 10, 6, 6, 8, 0).unwrap();
+        assert!(
+            (sunrise - expected).abs() < TimeDelta::minutes(3),
+            "{sunrise}"
+        );
+        let expected = Utc.with_ymd_and_hms(2023, 10, 6, 17, 28, 0).unwrap();
+        assert!(
+            (sunset - expected).abs() < TimeDelta::minutes(3),
+            "{sunset}"
+        );
+
+        // midnight sun
+        let date = NaiveDate::from_ymd_opt(2023, 6, 21).unwrap();
+        let times = sun_times(78., 15., date);
+        assert!(times.sunrise.is_none() && times.sunset.is_none());
+    }
+}
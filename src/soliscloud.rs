@@ -1,129 +1,380 @@
 use std::collections::HashMap;
-use std::env;
-use std::hash::Hash;
+use std::time::{Duration, Instant};
 
-use anyhow::{anyhow, bail, ensure, Context, Result};
-use base64::engine::general_purpose::STANDARD as b64;
-use base64::Engine;
-use chrono::{TimeDelta, TimeZone, Utc};
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, TimeDelta, TimeZone, Utc};
 use convert_case::{Case, Casing};
-use hmac::Mac;
-use log::warn;
+use log::{debug, info, warn};
 use reqwest::Client;
-use serde::de::DeserializeOwned;
-use serde::{Deserialize, Serialize};
+use serde::Deserialize;
 use serde_json::{json, Value};
 
+use self::client::SolisClient;
+use self::detail::InverterDetail;
+use self::error::ApiError;
+use self::unit::{convert, Unit};
 use crate::config::Solis;
 use crate::vm::{FullName, Obs};
 
-type HmacSha1 = hmac::Hmac<sha1::Sha1>;
+mod alarm;
+pub mod backfill;
+mod battery;
+mod client;
+pub mod control;
+mod detail;
+mod error;
+mod unit;
+
+/// How far back to look for alarms which haven't cleared.
+const ALARM_LOOKBACK_DAYS: i64 = 30;
 
 pub struct Service {
     config: Solis,
-    inverter_ids: Vec<String>,
+    client: SolisClient,
+    inverters: Vec<InverterLite>,
+    /// when `inverters` was last fetched; `None` forces a refresh on the next run
+    discovered: Option<Instant>,
+    /// by inverter id, the `code_N` states it was last seen in
+    unknown_states: HashMap<String, Vec<FullName>>,
+}
+
+impl Service {
+    /// A service which hasn't found its inverters yet; the first `run` looks for them.
+    pub fn new(http: &Client, config: Solis) -> Self {
+        Service {
+            client: SolisClient::new(http.clone(), &config),
+            config,
+            inverters: Vec::new(),
+            discovered: None,
+            unknown_states: HashMap::new(),
+        }
+    }
 }
 
 pub async fn warmup(http: &Client, config: Solis) -> Result<Service> {
-    let resp = call_api::<Resp<AllInverters>>(
-        &http,
-        &config,
-        "/v1/api/inverterList",
-        &json!({
-                "pageNo": 1,
-                "pageSize": 10,
-        }),
-    )
-    .await?;
-
-    let inverter_ids = resp
-        .data
-        .page
-        .records
-        .iter()
-        .map(|i| i.id.clone())
-        .collect::<Vec<_>>();
-
-    Ok(Service {
-        config,
-        inverter_ids,
-    })
+    let mut svc = Service::new(http, config);
+    svc.inverters = svc.client.inverters().await?;
+    svc.discovered = Some(Instant::now());
+    Ok(svc)
 }
 
-pub async fn run(http: &Client, solis: &Service) -> Result<Vec<(FullName, Obs)>> {
-    let mut ret = Vec::with_capacity(300);
-    for id in &solis.inverter_ids {
-        let mut resp = call_api::<Resp<HashMap<String, Value>>>(
-            &http,
-            &solis.config,
-            "/v1/api/inverterDetail",
-            &json!({
-                "id": id,
-            }),
-        )
-        .await?;
+/// Refresh the inverter list, if it's due; failure leaves the old list in place.
+async fn rediscover(solis: &mut Service) {
+    let interval = Duration::from_secs(solis.config.rediscover_secs);
+    if solis
+        .discovered
+        .is_some_and(|when| when.elapsed() < interval)
+    {
+        return;
+    }
 
-        let ts = match resp.data.remove("dataTimestamp") {
-            Some(Value::String(s)) => s.parse::<i64>().ok(),
-            Some(Value::Number(n)) => n.as_i64(),
-            _ => None,
+    match solis.client.inverters().await {
+        Ok(inverters) => {
+            let ids = |inverters: &[InverterLite]| {
+                inverters.iter().map(|i| i.id.clone()).collect::<Vec<_>>()
+            };
+            let (old, new) = (ids(&solis.inverters), ids(&inverters));
+            if old != new {
+                info!("inverters changed: {old:?} -> {new:?}");
+            }
+            solis.inverters = inverters;
+            solis.discovered = Some(Instant::now());
         }
-        .and_then(|n| Utc.timestamp_millis_opt(n).single());
+        Err(e) => warn!("rediscovering inverters failed, keeping old list: {e:?}"),
+    }
+}
+
+pub async fn run(solis: &mut Service) -> Result<Vec<(FullName, Obs)>> {
+    rediscover(solis).await;
 
-        let ts = match ts {
-            Some(ts) if (ts - Utc::now()).abs() < TimeDelta::minutes(10) => Some(ts),
-            Some(ts) => {
-                warn!("timestamp {ts:?} too far from now");
-                None
+    // one inverter, or the station list, failing shouldn't lose what the rest produced
+    let mut ret = Vec::with_capacity(300);
+    let mut failed = None;
+    for InverterLite { id, .. } in &solis.inverters {
+        let detail = match solis.client.inverter_detail(id).await {
+            Ok(detail) => detail,
+            Err(e) => {
+                if let Some(ApiError::NotFound) = e.downcast_ref() {
+                    // maybe it's been replaced; check next time
+                    solis.discovered = None;
+                }
+                let e = e.context(format!("fetching inverter {id}"));
+                warn!("{e:?}");
+                failed = Some(e);
+                continue;
+            }
+        };
+
+        let ts = data_timestamp(detail.data_timestamp.as_ref());
+        match detail_metrics(id, &detail, solis.config.battery_kwh, ts) {
+            Ok(mut metrics) => {
+                alarm::retire_unknown_states(&mut solis.unknown_states, id, &mut metrics, ts);
+                ret.extend(metrics);
             }
-            None => {
-                warn!("no timestamp in response");
-                None
+            Err(e) => {
+                let e = e.context(format!("reading inverter {id}"));
+                warn!("{e:?}");
+                failed = Some(e);
             }
         }
-        .unwrap_or_else(Utc::now);
+    }
 
-        for (k, v) in map(&resp.data)? {
-            ret.push((
-                FullName::new(format!("soliscloud_{k}"), [("id", id)]),
-                Obs::new(v, ts),
-            ));
+    match solis.client.stations().await {
+        Ok(stations) => {
+            for station in &stations {
+                match station_metrics(station) {
+                    Ok(metrics) => ret.extend(metrics),
+                    Err(e) => {
+                        let e = e.context(format!("reading station {}", station.id));
+                        warn!("{e:?}");
+                        failed = Some(e);
+                    }
+                }
+            }
+        }
+        Err(e) => {
+            let e = e.context("fetching stations");
+            warn!("{e:?}");
+            failed = Some(e);
         }
     }
 
+    match failed {
+        Some(e) if ret.is_empty() => return Err(e),
+        _ => (),
+    }
+
+    // the list is of everything that's ever happened, cleared or not
+    let today = Utc::now().date_naive();
+    let alarms = solis
+        .client
+        .alarms(today - TimeDelta::days(ALARM_LOOKBACK_DAYS), today)
+        .await;
+    match alarms {
+        Ok(alarms) => ret.extend(alarm::alarm_metrics(&alarms, &solis.inverters, Utc::now())?),
+        Err(e) => warn!("fetching alarms failed: {e:?}"),
+    }
+
     Ok(ret)
 }
 
-pub fn map(detail: &HashMap<String, Value>) -> Result<HashMap<String, f64>> {
-    let (good, bad) = opinionated(detail)?;
-    let mut ret = HashMap::with_capacity(good.len() + bad.len());
-    for (k, v) in good {
-        ret.insert(format!("soliscloud_{k}"), v);
+fn detail_metrics(
+    id: &str,
+    detail: &InverterDetail,
+    battery_kwh: Option<f64>,
+    ts: DateTime<Utc>,
+) -> Result<Vec<(FullName, Obs)>> {
+    let mut ret = Vec::with_capacity(200);
+    for reading in map(detail, battery_kwh)? {
+        let mut name = FullName::new(format!("soliscloud_{}", reading.name), [("id", id)])?;
+        for (k, v) in reading.labels {
+            name.add_label(k, v)?;
+        }
+        ret.push((name, Obs::new(reading.value, ts)));
+    }
+    Ok(ret)
+}
+
+/// A timestamp in milliseconds, which may be in a string.
+fn parse_millis(v: Option<&Value>) -> Option<DateTime<Utc>> {
+    match v {
+        Some(Value::String(s)) => s.parse::<i64>().ok(),
+        Some(Value::Number(n)) => n.as_i64(),
+        _ => None,
+    }
+    .and_then(|n| Utc.timestamp_millis_opt(n).single())
+}
+
+/// The time the data was collected, or now if that's missing or implausible.
+fn data_timestamp(v: Option<&Value>) -> DateTime<Utc> {
+    match parse_millis(v) {
+        Some(ts) if (ts - Utc::now()).abs() < TimeDelta::minutes(10) => Some(ts),
+        Some(ts) => {
+            warn!("timestamp {ts:?} too far from now");
+            None
+        }
+        None => {
+            warn!("no timestamp in response");
+            None
+        }
     }
+    .unwrap_or_else(Utc::now)
+}
+
+/// Totals for a whole station (plant), labelled by station id.
+fn station_metrics(station: &Station) -> Result<Vec<(FullName, Obs)>> {
+    let ts = data_timestamp(station.data_timestamp.as_ref());
+    let mut ret = Vec::with_capacity(32);
+    let mut emit = |name: &str, value: f64| {
+        ret.push((
+            FullName::new(
+                format!("soliscloud_station_{name}"),
+                [("station", &station.id)],
+            )?,
+            Obs::new(value, ts),
+        ));
+        Ok::<_, anyhow::Error>(())
+    };
 
-    let bad = map_detail(&bad)?;
+    let capacity = convert(station.capacity, &station.capacity_str, Unit::KilowattPeak)?;
+    emit("capacity_kwp", capacity)?;
+    emit(
+        "power_w",
+        convert(station.power, &station.power_str, Unit::Watt)?,
+    )?;
+
+    for (period, value, unit) in [
+        ("today", station.day_energy, &station.day_energy_str),
+        ("month", station.month_energy, &station.month_energy_str),
+        ("year", station.year_energy, &station.year_energy_str),
+        ("total", station.all_energy, &station.all_energy_str),
+    ] {
+        let value = convert(value, unit, Unit::KilowattHour)
+            .with_context(|| anyhow!("processing {period:?}"))?;
+        emit(&format!("energy_generated_{period}_kwh"), value)?;
+    }
 
-    for (k, v) in bad {
-        if let Some(v) = v.parse::<f64>().ok() {
-            ret.insert(format!("soliscloud_raw_{k}"), v);
+    // these have no unit fields, and are kWh in practice
+    for (name, value) in [
+        ("battery_charge_today", station.battery_today_charge_energy),
+        ("battery_charge_total", station.battery_total_charge_energy),
+        (
+            "battery_discharge_today",
+            station.battery_today_discharge_energy,
+        ),
+        (
+            "battery_discharge_total",
+            station.battery_total_discharge_energy,
+        ),
+        ("grid_purchased_today", station.grid_purchased_today_energy),
+        ("grid_purchased_total", station.grid_purchased_total_energy),
+        ("grid_sell_today", station.grid_sell_today_energy),
+        ("grid_sell_total", station.grid_sell_total_energy),
+        ("home_load_today", station.home_load_today_energy),
+        ("home_load_total", station.home_load_total_energy),
+    ] {
+        if let Some(value) = value {
+            emit(&format!("energy_{name}_kwh"), value)?;
         }
     }
 
+    for (period, value) in [("today", station.day_income), ("total", station.all_income)] {
+        let Some(value) = value else { continue };
+        ret.push((
+            FullName::new(
+                format!("soliscloud_station_income_{period}"),
+                [("station", &station.id), ("currency", &station.money)],
+            )?,
+            Obs::new(value, ts),
+        ));
+    }
+
     Ok(ret)
 }
 
-#[derive(Deserialize)]
-struct Resp<T> {
-    code: String,
-    msg: String,
-    data: T,
-    success: bool,
+/// One value from an inverter's detail; `labels` pick out a string, phase, etc.
+#[derive(Debug, PartialEq)]
+pub struct Reading {
+    pub name: String,
+    pub labels: Vec<(&'static str, String)>,
+    pub value: f64,
 }
 
-#[derive(Deserialize)]
-struct AllInverters {
-    page: Pager<InverterLite>,
-    // incomplete
+pub fn map(detail: &InverterDetail, battery_kwh: Option<f64>) -> Result<Vec<Reading>> {
+    let mut ret = Vec::with_capacity(100);
+    ret.extend(alarm::status_readings(&detail.status));
+    ret.extend(battery::battery_readings(detail, battery_kwh));
+    let mut emit = |name: String, label: Option<(&'static str, String)>, value: f64| {
+        ret.push(Reading {
+            name,
+            labels: label.into_iter().collect(),
+            value,
+        })
+    };
+
+    for (class, periods) in [
+        ("backup", &detail.backup),
+        ("grid_purchased", &detail.grid_purchased),
+        ("grid_sell", &detail.grid_sell),
+        ("home_grid", &detail.home_grid),
+        ("home_load", &detail.home_load),
+        ("generator", &detail.generator),
+        ("battery_charge", &detail.battery_charge),
+        ("battery_discharge", &detail.battery_discharge),
+    ] {
+        for (period, energy) in periods.iter() {
+            emit(format!("energy_{class}_{period}_kwh"), None, energy.kwh());
+        }
+    }
+
+    for (name, power) in [
+        ("battery_power_w", detail.battery_power),
+        ("epm_power_w", detail.epm_power),
+        ("epm_set_power_w", detail.epm_set_power),
+        ("bypass_load_power_w", detail.bypass_load_power),
+        ("sum_power_w", detail.sum_power),
+        ("sum_cal_power_w", detail.sum_cal_power),
+        ("total_power_w", detail.total_power),
+        ("family_load_power_w", detail.family_load_power),
+        ("generator_power_w", detail.generator_power),
+        ("total_load_power_w", detail.total_load_power),
+        ("ac_power_w", detail.ac_power),
+    ] {
+        if let Some(power) = power {
+            emit(name.to_string(), None, power.w());
+        }
+    }
+
+    if let Some(temp) = detail.inverter_temperature {
+        emit("inverter_temperature_c".to_string(), None, temp.c());
+    }
+    if let Some(soc) = detail.battery.soc {
+        emit("battery_soc".to_string(), None, soc);
+    }
+
+    for (i, string) in detail.strings.iter().enumerate() {
+        let label = || Some(("string", (i + 1).to_string()));
+        for (name, value) in [
+            ("string_voltage_v", string.voltage.map(|v| v.v())),
+            ("string_current_a", string.current.map(|c| c.a())),
+            ("string_power_w", string.power.map(|p| p.w())),
+        ] {
+            if let Some(value) = value {
+                emit(name.to_string(), label(), value);
+            }
+        }
+    }
+
+    for (phase, p) in ["a", "b", "c"].into_iter().zip(&detail.phases) {
+        let label = || Some(("phase", phase.to_string()));
+        for (name, value) in [
+            ("phase_ac_voltage_v", p.ac_voltage.map(|v| v.v())),
+            ("phase_ac_current_a", p.ac_current.map(|c| c.a())),
+            ("phase_grid_voltage_v", p.grid_voltage.map(|v| v.v())),
+            ("phase_grid_current_a", p.grid_current.map(|c| c.a())),
+            ("phase_power_factor", p.power_factor),
+            ("phase_reactive_power_var", p.reactive_power),
+            ("phase_apparent_power_va", p.apparent_power),
+            ("phase_bypass_voltage_v", p.bypass_voltage.map(|v| v.v())),
+            ("phase_bypass_current_a", p.bypass_current.map(|c| c.a())),
+        ] {
+            if let Some(value) = value {
+                emit(name.to_string(), label(), value);
+            }
+        }
+    }
+
+    let mut unmapped = detail.other.keys().collect::<Vec<_>>();
+    unmapped.sort();
+    debug!("unmapped inverter detail fields: {unmapped:?}");
+
+    for (k, v) in map_detail(&detail.other)? {
+        if let Ok(v) = v.parse::<f64>() {
+            emit(format!("raw_{k}"), None, v);
+        }
+    }
+
+    Ok(ret)
 }
 
 #[derive(Deserialize)]
@@ -133,48 +384,37 @@ struct InverterLite {
     // incomplete
 }
 
-#[derive(Deserialize)]
-struct Pager<T> {
-    records: Vec<T>,
-    total: i64,
-    // incomplete
-}
-
 #[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
 struct Station {
-    sno: String,
     id: String,
-}
-
-async fn call_api<T: DeserializeOwned>(
-    http: &Client,
-    cfg: &Solis,
-    path: &str,
-    data: &impl Serialize,
-) -> Result<T> {
-    let data = serde_json::to_vec(&data)?;
-    let md5 = b64.encode(md5::compute(&data).0);
-    // TODO: +0000 instead of 'GMT'? Doesn't seem to care
-    let now = Utc::now().to_rfc2822();
-    let param = format!("POST\n{md5}\napplication/json\n{now}\n{path}");
-    let mut mac = HmacSha1::new_from_slice(cfg.secret.as_bytes())?;
-    mac.update(param.as_bytes());
-    let signature = b64.encode(mac.finalize().into_bytes());
-    let resp = http
-        .post(format!("{}{path}", cfg.api))
-        .header("Content-Type", "application/json;charset=utf-8")
-        .header("Date", now)
-        .header("Authorization", format!("API {}:{signature}", cfg.key))
-        .header("Content-MD5", md5)
-        .body(data)
-        .send()
-        .await?
-        .error_for_status()?;
-    Ok(resp.json::<T>().await?)
-}
-
-fn env_var(name: &'static str) -> Result<String> {
-    env::var(name).with_context(|| anyhow!("reading env var {name:?}"))
+    data_timestamp: Option<Value>,
+    capacity: f64,
+    capacity_str: String,
+    power: f64,
+    power_str: String,
+    day_energy: f64,
+    day_energy_str: String,
+    month_energy: f64,
+    month_energy_str: String,
+    year_energy: f64,
+    year_energy_str: String,
+    all_energy: f64,
+    all_energy_str: String,
+    battery_today_charge_energy: Option<f64>,
+    battery_today_discharge_energy: Option<f64>,
+    battery_total_charge_energy: Option<f64>,
+    battery_total_discharge_energy: Option<f64>,
+    grid_purchased_today_energy: Option<f64>,
+    grid_purchased_total_energy: Option<f64>,
+    grid_sell_today_energy: Option<f64>,
+    grid_sell_total_energy: Option<f64>,
+    home_load_today_energy: Option<f64>,
+    home_load_total_energy: Option<f64>,
+    day_income: Option<f64>,
+    all_income: Option<f64>,
+    money: String,
+    // incomplete
 }
 
 fn map_detail(detail: &HashMap<String, Value>) -> Result<HashMap<String, String>> {
@@ -187,27 +427,22 @@ fn map_detail(detail: &HashMap<String, Value>) -> Result<HashMap<String, String>
     };
 
     for (unit_field, unit) in detail.iter() {
-        if !unit_field.ends_with("Str") {
+        let Some(real_field) = unit_field
+            .strip_suffix("Str")
+            .or_else(|| unit_field.strip_suffix("Unit"))
+        else {
             continue;
-        }
+        };
         if unit_field.contains("Time") {
             continue;
         }
-        let real_field = unit_field.trim_end_matches("Str");
         let Some(unit) = unit.as_str() else { continue };
-        let Some(real_value) = rem.remove(real_field) else {
+        // e.g. `timeZoneStr`, a display version of `timeZone`
+        let Ok((mul, unit)) = unit::parse(unit) else {
             continue;
         };
-        let unit = unit.replace(|c: char| !c.is_ascii_alphanumeric(), "");
-        if unit.is_empty() {
+        let Some(real_value) = rem.remove(real_field) else {
             continue;
-        }
-
-        let (mul, unit) = match unit.as_str() {
-            "Wh" => (0.001, "kWh"),
-            "MWh" => (1_000., "kWh"),
-            "GWh" => (1_000_000., "kWh"),
-            other => (1., other),
         };
 
         let real_value = match real_value {
@@ -219,11 +454,7 @@ fn map_detail(detail: &HashMap<String, Value>) -> Result<HashMap<String, String>
 
         rem.remove(unit_field);
         m.insert(
-            format!(
-                "{}_{}",
-                real_field.to_case(Case::Snake),
-                unit.to_ascii_lowercase()
-            ),
+            format!("{}_{}", real_field.to_case(Case::Snake), unit.suffix()),
             to_str(real_value),
         );
     }
@@ -235,166 +466,13 @@ fn map_detail(detail: &HashMap<String, Value>) -> Result<HashMap<String, String>
     Ok(m)
 }
 
-fn opinionated(
-    detail: &HashMap<String, Value>,
-) -> Result<(HashMap<String, f64>, HashMap<String, Value>)> {
-    let mut rem = detail.clone();
-
-    // leaving just 0 (the primary / only mppt string)
-    for i in 1..40 {
-        for k in &[
-            "iPv", "mpptIpv", "mpptIpv", "mpptPow", "mpptUpv", "pow", "uPv",
-        ] {
-            rem.remove(&format!("{k}{i}"));
-            rem.remove(&format!("{k}{i}Str"));
-        }
-    }
-
-    for secret in ["sn", "sno", "userId"] {
-        let _ = rem.remove(secret);
-    }
-
-    let mut m = HashMap::with_capacity(100);
-    let mut with_units = HashMap::with_capacity(200);
-    for (k, _) in rem.clone() {
-        if k.ends_with("Str") {
-            continue;
-        }
-        if k.contains("Time") {
-            continue;
-        }
-        let unit = match rem.remove(&format!("{}Str", k)) {
-            Some(unit) => unit,
-            None => match rem.remove(&format!("{}Unit", k)) {
-                Some(unit) => unit,
-                None => continue,
-            },
-        };
-
-        let value = rem.remove(&k).expect("unmodified input list");
-        let value = value
-            .as_f64()
-            .ok_or_else(|| anyhow!("value for {k} not a number: {value:?}"))?;
-        let unit = unit.as_str().ok_or_else(|| anyhow!("unit not a string"))?;
-        with_units.insert(k, (value, unit.to_string()));
-    }
-
-    let periods = ["Total", "Year", "Month", "Yesterday", "Today", ""];
-
-    for class in [
-        "backup",
-        "gridPurchased",
-        "gridSell",
-        "homeGrid",
-        "homeLoad",
-        "generator",
-    ] {
-        for period in periods {
-            let k = format!("{class}{period}Energy");
-            let Some((value, unit)) = with_units.remove(&k) else {
-                continue;
-            };
-            let value = to_kwh(value, &unit).with_context(|| anyhow!("processing {k:?}"))?;
-            m.insert(
-                format!(
-                    "energy_{}_{}_kwh",
-                    class.to_case(Case::Snake),
-                    period.to_ascii_lowercase()
-                ),
-                value,
-            );
-        }
-    }
-
-    for direction in ["Charge", "Discharge"] {
-        for period in periods {
-            let k = format!("battery{period}{direction}Energy");
-            let Some((value, unit)) = with_units.remove(&k) else {
-                continue;
-            };
-            let value = to_kwh(value, &unit).with_context(|| anyhow!("processing {k:?}"))?;
-            m.insert(
-                format!(
-                    "energy_battery_{}_{}_kwh",
-                    direction.to_ascii_lowercase(),
-                    period.to_ascii_lowercase()
-                ),
-                value,
-            );
-        }
-    }
-
-    for (old, new) in [
-        ("batteryPower", "battery_power_w"),
-        // not sure what any of these are, but they have units
-        // export power management?
-        ("pEpm", "epm_power_w"),
-        ("pEpmSet", "epm_set_power_w"),
-        ("bypassLoadPower", "bypass_load_power_w"),
-        ("psum", "sum_power_w"),
-        ("psumCal", "sum_cal_power_w"),
-        ("powTotal", "total_power_w"),
-        ("familyLoadPower", "family_load_power_w"),
-        ("generatorPower", "generator_power_w"),
-        ("totalLoadPower", "total_load_power_w"),
-        ("pac", "ac_power_w"),
-    ] {
-        if let Some((val, unit)) = with_units.remove(old) {
-            let val = to_watt(val, &unit).with_context(|| anyhow!("processing {old:?}"))?;
-            m.insert(new.to_string(), val);
-        }
-    }
-
-    if let Some((val, unit)) = with_units.remove("inverterTemperature") {
-        ensure!(
-            unit.chars().any(|c| matches!(c, 'C' | '℃')),
-            "inverter temperature unit not C: {unit:?}"
-        );
-        m.insert("inverter_temperature_c".to_string(), val);
-    }
-
-    if let Some(v) = rem.remove("batteryCapacitySoc") {
-        m.insert(
-            "battery_soc".to_string(),
-            v.as_f64().ok_or_else(|| anyhow!("non-numeric soc"))?,
-        );
-    }
-
-    println!("{:#?}", with_units);
-
-    // HACK: restoring 'rem', so the legacy support can continue to work
-    for (k, (value, unit)) in with_units {
-        rem.insert(format!("{k}Str"), json!(unit));
-        rem.insert(k, json!(value));
-    }
-
-    Ok((m, rem))
-}
-
-fn to_kwh(v: f64, unit: &str) -> Result<f64> {
-    Ok(match unit {
-        "Wh" => v * 0.001,
-        "kWh" => v,
-        "MWh" => v * 1_000.,
-        "GWh" => v * 1_000_000.,
-        other => bail!("unknown energy unit: {other:?} for value {v:?}"),
-    })
-}
-
-fn to_watt(v: f64, unit: &str) -> Result<f64> {
-    Ok(match unit {
-        "W" => v,
-        "kW" => v * 1_000.,
-        "MW" => v * 1_000_000.,
-        "GW" => v * 1_000_000_000.,
-        other => bail!("unknown power unit: {other:?} for value {v:?}"),
-    })
-}
-
 #[cfg(test)]
 mod tests {
     use anyhow::Result;
-    use std::collections::HashMap;
+    use serde_json::json;
+
+    use super::InverterDetail;
+    use crate::testing::stand_in;
 
     #[test]
     fn test_detail() -> Result<()> {
@@ -410,15 +488,70 @@ mod tests {
 
     #[test]
     fn test_opinion() -> Result<()> {
-        let (good, bad) = super::opinionated(&serde_json::from_str(include_str!(
-            "../tests/ref/soliscloud/inverterDetail.json"
-        ))?)?;
-        let bad = super::map_detail(&bad)?;
-        assert_eq!(good.get("energy_home_load_today_kwh"), Some(&6.1));
-        println!("{:#?}", bad);
-        assert_eq!(bad, HashMap::new());
-        assert_eq!(bad.get("family_load_power_kw"), Some(&"0.809".to_string()));
-        assert_eq!(bad.get("family_load_power_pec"), Some(&"1".to_string()));
+        let detail: InverterDetail =
+            serde_json::from_str(include_str!("../tests/ref/soliscloud/inverterDetail.json"))?;
+        assert_eq!(detail.home_load.today.map(|e| e.kwh()), Some(6.1));
+        assert_eq!(detail.family_load_power.map(|p| p.w()), Some(809.));
+        assert_eq!(detail.inverter_temperature.map(|t| t.c()), Some(37.7));
+        assert_eq!(detail.strings.len(), 2);
+        assert_eq!(detail.phases.len(), 1);
+        assert_eq!(detail.other.get("familyLoadPowerPec"), Some(&json!("1")));
+        for gone in [
+            "sn",
+            "id",
+            "stationId",
+            "collectorId",
+            "collectorsn",
+            "uPv1",
+            "uPv3Str",
+            "mpptIpv1",
+            "aPhasePowerFactor",
+            "bypassAcVoltageB",
+            "homeLoadTodayEnergyStr",
+        ] {
+            assert_eq!(detail.other.get(gone), None, "{gone}");
+        }
+
+        let flipped: InverterDetail =
+            serde_json::from_str(include_str!("../tests/ref/soliscloud/unitFlip.json"))?;
+        assert_eq!(flipped.home_load.total.map(|e| e.kwh()), Some(1377.));
+        assert_eq!(flipped.home_load.yesterday.map(|e| e.kwh()), Some(12.9));
+        assert!(flipped.other.is_empty(), "{:?}", flipped.other);
+
+        for generating in [
+            include_str!("../tests/ref/soliscloud/2024-03-07T12-57-detail-gen.json"),
+            include_str!("../tests/ref/soliscloud/2024-03-07T14-27-detail-gen.json"),
+        ] {
+            let detail: InverterDetail = serde_json::from_str(generating)?;
+            assert!(detail.ac_power.is_some_and(|p| p.w() > 0.));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_strings_and_phases() -> Result<()> {
+        let detail =
+            serde_json::from_str(include_str!("../tests/ref/soliscloud/inverterDetail.json"))?;
+        let m = super::map(&detail, None)?;
+        let get = |name: &str, label: (&'static str, &str)| {
+            m.iter()
+                .find(|r| r.name == name && r.labels == [(label.0, label.1.to_string())])
+                .map(|r| r.value)
+        };
+        assert_eq!(get("string_voltage_v", ("string", "1")), Some(252.4));
+        assert_eq!(get("string_current_a", ("string", "1")), Some(1.8));
+        assert_eq!(get("string_power_w", ("string", "1")), Some(454.));
+        assert_eq!(get("string_power_w", ("string", "2")), Some(0.));
+        assert_eq!(get("string_power_w", ("string", "3")), None);
+        assert_eq!(get("phase_ac_voltage_v", ("phase", "a")), Some(235.7));
+        assert_eq!(get("phase_reactive_power_var", ("phase", "a")), Some(347.));
+        assert_eq!(get("phase_bypass_current_a", ("phase", "a")), Some(3.7));
+        assert_eq!(get("phase_ac_voltage_v", ("phase", "b")), None);
+        let leftover = m
+            .iter()
+            .filter(|r| r.name.contains("_pv_") || r.name.contains("mppt"))
+            .collect::<Vec<_>>();
+        assert!(leftover.is_empty(), "{leftover:?}");
         Ok(())
     }
 
@@ -438,6 +571,176 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_stations() -> Result<()> {
+        let resp: super::client::Resp<super::client::Listing<super::Station>> =
+            serde_json::from_str(include_str!("../tests/ref/soliscloud/userStationList.json"))?;
+        let station = &resp.data.into_page().records[0];
+        let m = super::station_metrics(station)?;
+        let get = |name: &str| {
+            m.iter()
+                .find(|(n, _)| n.name() == name)
+                .map(|(n, o)| (n.label("station").unwrap().to_string(), o.value()))
+        };
+        assert_eq!(
+            get("soliscloud_station_capacity_kwp"),
+            Some(("3234567890123456789".to_string(), 4.))
+        );
+        assert_eq!(
+            get("soliscloud_station_energy_generated_total_kwh").map(|(_, v)| v),
+            Some(25.)
+        );
+        assert_eq!(
+            get("soliscloud_station_power_w").map(|(_, v)| v),
+            Some(154.)
+        );
+        assert_eq!(
+            get("soliscloud_station_energy_battery_discharge_total_kwh").map(|(_, v)| v),
+            Some(6.)
+        );
+        Ok(())
+    }
+
+    fn config(api: String) -> crate::config::Solis {
+        crate::config::Solis {
+            api,
+            key: "123".to_string(),
+            secret: "sekrit".to_string(),
+            interval_secs: 60,
+            rediscover_secs: 60,
+            battery_kwh: None,
+            attempts: 1,
+            retry_delay_ms: 1,
+            allow_control: false,
+            readback_timeout_ms: 0,
+        }
+    }
+
+    fn service(api: String, ids: &[&str]) -> super::Service {
+        let mut solis = super::Service::new(&reqwest::Client::new(), config(api));
+        solis.inverters = ids
+            .iter()
+            .map(|id| super::InverterLite {
+                id: id.to_string(),
+                sn: format!("SN{id}"),
+            })
+            .collect();
+        solis.discovered = Some(std::time::Instant::now());
+        solis
+    }
+
+    #[tokio::test]
+    async fn test_run_partial() -> Result<()> {
+        let detail = format!(
+            r#"{{"success":true,"code":"0","msg":"success","data":{}}}"#,
+            include_str!("../tests/ref/soliscloud/inverterDetail.json")
+        );
+        let missing = r#"{"success":false,"code":"1","msg":"inverter does not exist"}"#;
+        let stations = include_str!("../tests/ref/soliscloud/userStationList.json")
+            .replace(r#""capacityStr": "kWp""#, r#""capacityStr": "furlongs""#);
+
+        // the first inverter has gone, the station is garbled, and alarms are broken
+        let (url, _) = stand_in(&[(404, missing), (200, &detail), (200, &stations), (500, "")])?;
+        let mut solis = service(url, &["1", "2"]);
+        let m = super::run(&mut solis).await?;
+        assert!(!m.is_empty());
+        assert!(m.iter().all(|(n, _)| n.label("id") == Some("2")));
+        assert!(solis.discovered.is_none());
+
+        let (url, _) = stand_in(&[(500, ""), (500, "")])?;
+        assert!(super::run(&mut service(url, &["1"])).await.is_err());
+        Ok(())
+    }
+
+    fn listing(id: &str) -> String {
+        format!(
+            r#"{{"success":true,"code":"0","msg":"success","data":{{"page":{{
+                "records":[{{"id":"{id}","sn":"SN{id}"}}],"total":1,"pages":1}}}}}}"#
+        )
+    }
+
+    fn ids(solis: &super::Service) -> Vec<&str> {
+        solis.inverters.iter().map(|i| i.id.as_str()).collect()
+    }
+
+    fn paths(rx: std::sync::mpsc::Receiver<(String, String)>) -> Vec<String> {
+        rx.iter()
+            .map(|(headers, _)| headers.split(' ').nth(1).unwrap_or_default().to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_rediscover() -> Result<()> {
+        let detail = format!(
+            r#"{{"success":true,"code":"0","msg":"success","data":{}}}"#,
+            include_str!("../tests/ref/soliscloud/inverterDetail.json")
+        );
+        let missing = r#"{"success":false,"code":"1","msg":"inverter does not exist"}"#;
+
+        // the inverter has been replaced: it's looked for again on the next run
+        let (url, rx) = stand_in(&[
+            (404, missing),
+            (500, ""),
+            (200, &listing("2")),
+            (200, &detail),
+            (500, ""),
+            (500, ""),
+        ])?;
+        let mut solis = service(url, &["1"]);
+        assert!(super::run(&mut solis).await.is_err());
+        assert!(solis.discovered.is_none());
+        assert!(!super::run(&mut solis).await?.is_empty());
+        assert_eq!(ids(&solis), ["2"]);
+        assert!(solis.discovered.is_some());
+        assert_eq!(
+            paths(rx),
+            [
+                "/v1/api/inverterDetail",
+                "/v1/api/userStationList",
+                "/v1/api/inverterList",
+                "/v1/api/inverterDetail",
+                "/v1/api/userStationList",
+                "/v1/api/alarmList",
+            ]
+        );
+
+        // a daemon started while SolisCloud was down finds its inverters once it's back
+        let (url, rx) = stand_in(&[
+            (500, ""),
+            (500, ""),
+            (200, &listing("4")),
+            (200, &detail),
+            (500, ""),
+            (500, ""),
+        ])?;
+        let mut solis = super::Service::new(&reqwest::Client::new(), config(url));
+        assert!(super::run(&mut solis).await.is_err());
+        assert!(solis.discovered.is_none());
+        assert!(!super::run(&mut solis).await?.is_empty());
+        assert_eq!(ids(&solis), ["4"]);
+        assert_eq!(
+            paths(rx)[..4],
+            [
+                "/v1/api/inverterList",
+                "/v1/api/userStationList",
+                "/v1/api/inverterList",
+                "/v1/api/inverterDetail",
+            ]
+        );
+
+        // and every `rediscover_secs` regardless
+        let (url, rx) = stand_in(&[(200, &listing("3")), (200, &detail), (500, ""), (500, "")])?;
+        let mut solis = service(url, &["1"]);
+        solis.config.rediscover_secs = 0;
+        super::run(&mut solis).await?;
+        assert_eq!(ids(&solis), ["3"]);
+        assert_eq!(
+            paths(rx)[..2],
+            ["/v1/api/inverterList", "/v1/api/inverterDetail"]
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_to_string() {
         assert_eq!("\"hello\"", serde_json::json!("hello").to_string());
@@ -0,0 +1,259 @@
+//! What's wrong: the state fields of the inverter detail, and the alarm list.
+//!
+//! States are one-hot, `soliscloud_inverter_state{state="online"} 1`, with a `0` for
+//! every other known state, so the old state doesn't linger in queries. Codes we don't
+//! know the meaning of come out as `state="code_N"`, and are set to `0` on the next
+//! reading which doesn't have them. `bmsState` is documented nowhere, so it's only
+//! ever `code_N`.
+
+use std::collections::HashMap;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+use super::detail::Status;
+use super::{InverterLite, Reading};
+use crate::vm::{FullName, Obs};
+
+/// `state`
+const STATES: &[(u64, &str)] = &[(1, "online"), (2, "offline"), (3, "alarm")];
+
+/// `currentState`; the rest are fault codes, which also show up in the alarm list
+const OPERATING_STATES: &[(u64, &str)] = &[
+    (0, "waiting"),
+    (1, "open_run"),
+    (2, "soft_run"),
+    (3, "generating"),
+];
+
+/// `stateExceptionFlag`: whether the inverter last went offline as expected
+const EXCEPTIONS: &[(u64, &str)] = &[(0, "normal"), (1, "abnormal")];
+
+pub fn status_readings(status: &Status) -> Vec<Reading> {
+    let mut ret = Vec::with_capacity(16);
+    for (name, known, code) in [
+        ("inverter_state", STATES, status.state),
+        (
+            "inverter_operating_state",
+            OPERATING_STATES,
+            status.current_state,
+        ),
+        ("inverter_exception", EXCEPTIONS, status.exception),
+        ("bms_state", &[], status.bms_state),
+    ] {
+        if let Some(code) = code {
+            ret.extend(one_hot(name, known, code));
+        }
+    }
+
+    for (source, bits) in &status.faults {
+        ret.push(Reading {
+            name: "fault_active".to_string(),
+            labels: vec![("source", source.to_string())],
+            value: if *bits == 0 { 0. } else { 1. },
+        });
+        for bit in (0..64).filter(|bit| bits & (1 << bit) != 0) {
+            ret.push(Reading {
+                name: "fault_bit".to_string(),
+                labels: vec![("source", source.to_string()), ("bit", bit.to_string())],
+                value: 1.,
+            });
+        }
+    }
+
+    ret
+}
+
+fn one_hot(name: &str, known: &[(u64, &str)], code: u64) -> Vec<Reading> {
+    let reading = |state: String, on: bool| Reading {
+        name: name.to_string(),
+        labels: vec![("state", state)],
+        value: if on { 1. } else { 0. },
+    };
+    let mut ret = known
+        .iter()
+        .map(|(c, state)| reading(state.to_string(), *c == code))
+        .collect::<Vec<_>>();
+    if !known.iter().any(|(c, _)| *c == code) {
+        ret.push(reading(format!("code_{code}"), true));
+    }
+    ret
+}
+
+/// Set the `code_N` states an inverter was in last time to `0`, if it's left them, as
+/// nothing else will.
+///
+/// `last` is keyed by inverter id, and updated to the unknown states in `produced`.
+pub fn retire_unknown_states(
+    last: &mut HashMap<String, Vec<FullName>>,
+    id: &str,
+    produced: &mut Vec<(FullName, Obs)>,
+    ts: DateTime<Utc>,
+) {
+    let unknown = produced
+        .iter()
+        .filter(|(name, _)| name.label("state").is_some_and(|s| s.starts_with("code_")))
+        .map(|(name, _)| name.clone())
+        .collect::<Vec<_>>();
+    let last = last.entry(id.to_string()).or_default();
+    for name in last.drain(..) {
+        if !unknown.contains(&name) {
+            produced.push((name, Obs::new(0., ts)));
+        }
+    }
+    *last = unknown;
+}
+
+/// A record from `/v1/api/alarmList`.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Alarm {
+    station_id: String,
+    alarm_device_sn: String,
+    alarm_code: String,
+    /// 1: tip, 2: general, 3: emergency
+    alarm_level: String,
+    alarm_msg: String,
+    /// 0: pending, 1: processed, 2: restored
+    state: String,
+    // incomplete
+}
+
+/// `soliscloud_alarm_active 1` for each alarm which hasn't cleared yet.
+///
+/// The alarm is labelled with the inverter `id`, if it's one of ours, rather than the
+/// serial number.
+pub fn alarm_metrics(
+    alarms: &[Alarm],
+    inverters: &[InverterLite],
+    now: DateTime<Utc>,
+) -> Result<Vec<(FullName, Obs)>> {
+    alarms
+        .iter()
+        .filter(|alarm| alarm.state == "0")
+        .map(|alarm| {
+            let mut name = FullName::new(
+                "soliscloud_alarm_active",
+                [
+                    ("station", alarm.station_id.as_str()),
+                    ("code", &alarm.alarm_code),
+                    ("level", &alarm.alarm_level),
+                    ("message", &alarm.alarm_msg),
+                ],
+            )?;
+            if let Some(inverter) = inverters.iter().find(|i| i.sn == alarm.alarm_device_sn) {
+                name.add_label("id", &inverter.id)?;
+            }
+            Ok((name, Obs::new(1., now)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use chrono::Utc;
+
+    use super::*;
+    use crate::soliscloud::client::{Listing, Resp};
+    use crate::soliscloud::detail::InverterDetail;
+
+    #[test]
+    fn test_status() -> Result<()> {
+        let detail: InverterDetail = serde_json::from_str(include_str!(
+            "../../tests/ref/soliscloud/inverterDetail.json"
+        ))?;
+        let m = status_readings(&detail.status);
+        let get = |name: &str, labels: &[(&str, &str)]| {
+            m.iter()
+                .find(|r| {
+                    r.name == name
+                        && r.labels.len() == labels.len()
+                        && labels
+                            .iter()
+                            .all(|(k, v)| r.labels.contains(&(*k, v.to_string())))
+                })
+                .map(|r| r.value)
+        };
+        assert_eq!(get("inverter_state", &[("state", "online")]), Some(1.));
+        assert_eq!(get("inverter_state", &[("state", "alarm")]), Some(0.));
+        assert_eq!(
+            get("inverter_operating_state", &[("state", "generating")]),
+            Some(1.)
+        );
+        assert_eq!(get("bms_state", &[("state", "code_0")]), Some(1.));
+        assert_eq!(get("inverter_exception", &[("state", "normal")]), Some(1.));
+        assert_eq!(
+            get("inverter_exception", &[("state", "abnormal")]),
+            Some(0.)
+        );
+        assert_eq!(
+            get("fault_active", &[("source", "battery_failure_01")]),
+            Some(0.)
+        );
+        assert!(!m.iter().any(|r| r.name == "fault_bit"));
+
+        let mut status = detail.status;
+        status.faults = vec![("battery_alarm", 0x12)];
+        let bits = status_readings(&status)
+            .into_iter()
+            .filter(|r| r.name == "fault_bit")
+            .map(|r| r.labels[1].1.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(bits, ["1", "4"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_retire_unknown_states() -> Result<()> {
+        let state = |state: &str| {
+            FullName::new("soliscloud_bms_state", [("id", "1"), ("state", state)]).unwrap()
+        };
+        let now = Utc::now();
+        let mut last = HashMap::new();
+
+        let mut produced = vec![(state("code_5"), Obs::new(1., now))];
+        retire_unknown_states(&mut last, "1", &mut produced, now);
+        assert_eq!(produced.len(), 1);
+
+        // still there: nothing to do
+        retire_unknown_states(&mut last, "1", &mut produced, now);
+        assert_eq!(produced.len(), 1);
+
+        // moved on
+        let mut produced = vec![(state("code_6"), Obs::new(1., now))];
+        retire_unknown_states(&mut last, "1", &mut produced, now);
+        let zeroed = produced
+            .iter()
+            .filter(|(_, o)| o.value() == 0.)
+            .map(|(n, _)| n.label("state"))
+            .collect::<Vec<_>>();
+        assert_eq!(zeroed, [Some("code_5")]);
+
+        // another inverter's states are its own
+        let mut produced = Vec::new();
+        retire_unknown_states(&mut last, "2", &mut produced, now);
+        assert!(produced.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_alarms() -> Result<()> {
+        let resp: Resp<Listing<Alarm>> =
+            serde_json::from_str(include_str!("../../tests/ref/soliscloud/alarmList.json"))?;
+        let inverters = [InverterLite {
+            id: "1308675217949812345".to_string(),
+            sn: "REDACTED".to_string(),
+        }];
+        let m = alarm_metrics(&resp.data.into_page().records, &inverters, Utc::now())?;
+        assert_eq!(m.len(), 1, "only the uncleared one");
+        let (name, obs) = &m[0];
+        assert_eq!(name.name(), "soliscloud_alarm_active");
+        assert_eq!(name.label("message"), Some("NO-Grid"));
+        assert_eq!(name.label("level"), Some("3"));
+        assert_eq!(name.label("id"), Some("1308675217949812345"));
+        assert_eq!(obs.value(), 1.);
+        Ok(())
+    }
+}
@@ -0,0 +1,266 @@
+//! Filling gaps from the history endpoints, `disport-data backfill FROM TO`.
+//!
+//! `inverterDay` returns the same records as `inverterDetail`, every five minutes or so,
+//! so those come out as the usual series. `inverterMonth` and `inverterYear` only have
+//! energy totals, per day and per month, which come out as
+//! `soliscloud_history_energy_*_kwh{period="day"}` etc., stamped with the start of the
+//! day or month.
+
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{Datelike, NaiveDate};
+use log::{info, warn};
+use serde::Deserialize;
+use serde_json::Value;
+use tokio::time::{Instant, Interval, MissedTickBehavior};
+
+use super::detail::InverterDetail;
+use super::unit::{convert, Unit};
+use super::{detail_metrics, parse_millis, Service};
+use crate::vm::{FullName, Obs};
+
+/// The documented limit is a couple of calls a second; there's no hurry.
+const REQUEST_GAP: Duration = Duration::from_secs(1);
+
+/// One call's worth of history.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Span {
+    Day(NaiveDate),
+    Month(i32, u32),
+    Year(i32),
+}
+
+/// Every day, month and year touched by `from ..= to`.
+pub fn spans(from: NaiveDate, to: NaiveDate) -> Vec<Span> {
+    let mut ret = Vec::new();
+    for day in from.iter_days().take_while(|day| *day <= to) {
+        ret.push(Span::Day(day));
+        let month = Span::Month(day.year(), day.month());
+        if !ret.contains(&month) {
+            ret.push(month);
+        }
+        let year = Span::Year(day.year());
+        if !ret.contains(&year) {
+            ret.push(year);
+        }
+    }
+    ret
+}
+
+pub struct Backfill<'s> {
+    solis: &'s Service,
+    /// `money` is required by the history endpoints, and only the stations know it
+    currency: String,
+    limit: Interval,
+}
+
+impl<'s> Backfill<'s> {
+    pub async fn new(solis: &'s Service) -> Result<Self> {
+        let mut limit = tokio::time::interval_at(Instant::now(), REQUEST_GAP);
+        limit.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        limit.tick().await;
+
+        let stations = solis.client.stations().await?;
+        let Some(station) = stations.first() else {
+            bail!("no stations");
+        };
+        Ok(Backfill {
+            solis,
+            currency: station.money.clone(),
+            limit,
+        })
+    }
+
+    /// Everything for `span`, for every inverter.
+    pub async fn fetch(&mut self, span: Span) -> Result<Vec<(FullName, Obs)>> {
+        let client = &self.solis.client;
+        let money = &self.currency;
+        let mut ret = Vec::new();
+        for inverter in &self.solis.inverters {
+            let id = &inverter.id;
+            info!("backfilling {span:?} for {id}");
+            self.limit.tick().await;
+            let context = || anyhow!("fetching {span:?} for {id}");
+            match span {
+                Span::Day(day) => {
+                    let records = client
+                        .inverter_day(id, money, day)
+                        .await
+                        .with_context(context)?;
+                    ret.extend(day_metrics(id, &records, self.solis.config.battery_kwh)?);
+                }
+                Span::Month(year, month) => {
+                    let records = client
+                        .inverter_month(id, money, year, month)
+                        .await
+                        .with_context(context)?;
+                    ret.extend(total_metrics(id, "day", &records)?);
+                }
+                Span::Year(year) => {
+                    let records = client
+                        .inverter_year(id, money, year)
+                        .await
+                        .with_context(context)?;
+                    ret.extend(total_metrics(id, "month", &records)?);
+                }
+            }
+        }
+        Ok(ret)
+    }
+}
+
+fn day_metrics(
+    id: &str,
+    records: &[InverterDetail],
+    battery_kwh: Option<f64>,
+) -> Result<Vec<(FullName, Obs)>> {
+    let mut ret = Vec::new();
+    for record in records {
+        let Some(ts) = parse_millis(record.data_timestamp.as_ref()) else {
+            warn!("history record for {id} without a timestamp");
+            continue;
+        };
+        ret.extend(detail_metrics(id, record, battery_kwh, ts)?);
+    }
+    Ok(ret)
+}
+
+/// A record from `inverterMonth` (a day) or `inverterYear` (a month).
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Total {
+    date: Value,
+    energy: Option<f64>,
+    energy_str: Option<String>,
+    grid_purchased_energy: Option<f64>,
+    grid_purchased_energy_str: Option<String>,
+    grid_sell_energy: Option<f64>,
+    grid_sell_energy_str: Option<String>,
+    home_load_energy: Option<f64>,
+    home_load_energy_str: Option<String>,
+    battery_charge_energy: Option<f64>,
+    battery_charge_energy_str: Option<String>,
+    battery_discharge_energy: Option<f64>,
+    battery_discharge_energy_str: Option<String>,
+    // incomplete
+}
+
+fn total_metrics(id: &str, period: &str, records: &[Total]) -> Result<Vec<(FullName, Obs)>> {
+    let mut ret = Vec::new();
+    for record in records {
+        let ts = parse_millis(Some(&record.date))
+            .ok_or_else(|| anyhow!("history record without a date: {record:?}"))?;
+        for (class, value, unit) in [
+            ("generated", record.energy, &record.energy_str),
+            (
+                "grid_purchased",
+                record.grid_purchased_energy,
+                &record.grid_purchased_energy_str,
+            ),
+            (
+                "grid_sell",
+                record.grid_sell_energy,
+                &record.grid_sell_energy_str,
+            ),
+            (
+                "home_load",
+                record.home_load_energy,
+                &record.home_load_energy_str,
+            ),
+            (
+                "battery_charge",
+                record.battery_charge_energy,
+                &record.battery_charge_energy_str,
+            ),
+            (
+                "battery_discharge",
+                record.battery_discharge_energy,
+                &record.battery_discharge_energy_str,
+            ),
+        ] {
+            let (Some(value), Some(unit)) = (value, unit) else {
+                continue;
+            };
+            let kwh = convert(value, unit, Unit::KilowattHour)
+                .with_context(|| anyhow!("processing {class:?} for {ts}"))?;
+            ret.push((
+                FullName::new(
+                    format!("soliscloud_history_energy_{class}_kwh"),
+                    [("id", id), ("period", period)],
+                )?,
+                Obs::new(kwh, ts),
+            ));
+        }
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use chrono::{NaiveDate, TimeZone, Utc};
+
+    use super::super::client::Resp;
+    use super::*;
+
+    #[test]
+    fn test_spans() {
+        let date = |m, d| NaiveDate::from_ymd_opt(2023, m, d).unwrap();
+        assert_eq!(
+            spans(date(9, 30), date(10, 1)),
+            [
+                Span::Day(date(9, 30)),
+                Span::Month(2023, 9),
+                Span::Year(2023),
+                Span::Day(date(10, 1)),
+                Span::Month(2023, 10),
+            ]
+        );
+        assert!(spans(date(10, 2), date(10, 1)).is_empty());
+    }
+
+    #[test]
+    fn test_day() -> Result<()> {
+        let resp: Resp<Vec<InverterDetail>> =
+            serde_json::from_str(include_str!("../../tests/ref/soliscloud/inverterDay.json"))?;
+        let m = day_metrics("1", &resp.data, None)?;
+        let power = m
+            .iter()
+            .filter(|(n, _)| n.name() == "soliscloud_ac_power_w")
+            .map(|(_, o)| (o.when(), o.value()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            power,
+            [
+                (Utc.with_ymd_and_hms(2023, 10, 1, 12, 0, 0).unwrap(), 1230.),
+                (Utc.with_ymd_and_hms(2023, 10, 1, 12, 5, 0).unwrap(), 1410.),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_month() -> Result<()> {
+        let resp: Resp<Vec<Total>> = serde_json::from_str(include_str!(
+            "../../tests/ref/soliscloud/inverterMonth.json"
+        ))?;
+        let m = total_metrics("1", "day", &resp.data)?;
+        let (name, obs) = m
+            .iter()
+            .find(|(n, _)| n.name() == "soliscloud_history_energy_generated_kwh")
+            .unwrap();
+        assert_eq!(name.label("period"), Some("day"));
+        assert_eq!(
+            obs.when(),
+            Utc.with_ymd_and_hms(2023, 10, 1, 0, 0, 0).unwrap()
+        );
+        assert_eq!(obs.value(), 11.2);
+        let (_, obs) = m
+            .iter()
+            .find(|(n, _)| n.name() == "soliscloud_history_energy_grid_sell_kwh")
+            .unwrap();
+        assert_eq!(obs.value(), 1.2);
+        Ok(())
+    }
+}
@@ -0,0 +1,129 @@
+//! The battery: health, what the BMS reports, and the limits configured on the inverter.
+
+use super::detail::{Battery, InverterDetail};
+use super::Reading;
+
+/// Readings for the battery, if there is one.
+///
+/// Cycles are only counted if the usable capacity, `battery_kwh`, is configured.
+pub fn battery_readings(detail: &InverterDetail, battery_kwh: Option<f64>) -> Vec<Reading> {
+    let Battery {
+        soc: _,
+        soh,
+        voltage,
+        current,
+        bms_power,
+        inverter_voltage,
+        inverter_current,
+        charge_limit,
+        discharge_limit,
+        max_charge_set,
+        max_discharge_set,
+        force_current_set,
+        float_voltage_set,
+        absorption_voltage_set,
+        over_voltage_set,
+        under_voltage_set,
+    } = &detail.battery;
+
+    let mut ret = Vec::with_capacity(20);
+    for (name, value) in [
+        ("battery_soh_pct", *soh),
+        ("battery_voltage_v", voltage.map(|v| v.v())),
+        ("battery_current_a", current.map(|c| c.a())),
+        ("battery_bms_power_w", bms_power.map(|p| p.w())),
+        (
+            "battery_inverter_voltage_v",
+            inverter_voltage.map(|v| v.v()),
+        ),
+        (
+            "battery_inverter_current_a",
+            inverter_current.map(|c| c.a()),
+        ),
+        ("battery_charge_limit_a", charge_limit.map(|c| c.a())),
+        ("battery_discharge_limit_a", discharge_limit.map(|c| c.a())),
+        ("battery_max_charge_set_a", max_charge_set.map(|c| c.a())),
+        (
+            "battery_max_discharge_set_a",
+            max_discharge_set.map(|c| c.a()),
+        ),
+        (
+            "battery_force_current_set_a",
+            force_current_set.map(|c| c.a()),
+        ),
+        (
+            "battery_float_voltage_set_v",
+            float_voltage_set.map(|v| v.v()),
+        ),
+        (
+            "battery_absorption_voltage_set_v",
+            absorption_voltage_set.map(|v| v.v()),
+        ),
+        (
+            "battery_over_voltage_set_v",
+            over_voltage_set.map(|v| v.v()),
+        ),
+        (
+            "battery_under_voltage_set_v",
+            under_voltage_set.map(|v| v.v()),
+        ),
+    ] {
+        if let Some(value) = value {
+            ret.push(Reading {
+                name: name.to_string(),
+                labels: Vec::new(),
+                value,
+            });
+        }
+    }
+
+    let charged = detail.battery_charge.total.map(|e| e.kwh());
+    let discharged = detail.battery_discharge.total.map(|e| e.kwh());
+    if let (Some(charged), Some(discharged)) = (charged, discharged) {
+        if charged > 0. {
+            ret.push(Reading {
+                name: "battery_round_trip_efficiency".to_string(),
+                labels: Vec::new(),
+                value: discharged / charged,
+            });
+        }
+    }
+    if let (Some(discharged), Some(capacity)) = (discharged, battery_kwh) {
+        ret.push(Reading {
+            name: "battery_equivalent_cycles".to_string(),
+            labels: Vec::new(),
+            value: discharged / capacity,
+        });
+    }
+
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::*;
+
+    #[test]
+    fn test_battery() -> Result<()> {
+        let detail: InverterDetail = serde_json::from_str(include_str!(
+            "../../tests/ref/soliscloud/inverterDetail.json"
+        ))?;
+        let m = battery_readings(&detail, Some(5.));
+        let get = |name: &str| m.iter().find(|r| r.name == name).map(|r| r.value);
+        assert_eq!(get("battery_soh_pct"), Some(100.));
+        assert_eq!(get("battery_current_a"), Some(6.8));
+        assert_eq!(get("battery_bms_power_w"), Some(338.));
+        assert_eq!(get("battery_inverter_current_a"), Some(-7.1));
+        assert_eq!(get("battery_discharge_limit_a"), Some(74.));
+        assert_eq!(get("battery_max_charge_set_a"), Some(29.6));
+        assert_eq!(get("battery_under_voltage_set_v"), Some(42.));
+        assert_eq!(get("battery_equivalent_cycles"), Some(148. / 5.));
+        assert!(!detail.other.contains_key("bstteryCurrent"));
+
+        let m = battery_readings(&detail, None);
+        assert!(!m.iter().any(|r| r.name == "battery_equivalent_cycles"));
+        Ok(())
+    }
+}
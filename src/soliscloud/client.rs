@@ -0,0 +1,423 @@
+//! Signed requests to the SolisCloud API, one method per endpoint.
+//!
+//! Every request is a `POST` of JSON, signed with HMAC-SHA1 over the method, the body's
+//! MD5, the content type, the `Date` header and the path.
+
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use base64::engine::general_purpose::STANDARD as b64;
+use base64::Engine;
+use chrono::{DateTime, NaiveDate, Utc};
+use hmac::Mac;
+use log::warn;
+use reqwest::Client;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_aux::prelude::*;
+use serde_json::{json, Value};
+
+use super::alarm::Alarm;
+use super::backfill::Total;
+use super::detail::InverterDetail;
+use super::error::ApiError;
+use super::{InverterLite, Station};
+use crate::config::Solis;
+
+type HmacSha1 = hmac::Hmac<sha1::Sha1>;
+
+/// Where the `Date` header comes from; fixed in tests so signatures can be checked.
+pub type Clock = fn() -> DateTime<Utc>;
+
+pub struct SolisClient {
+    http: Client,
+    api: String,
+    key: String,
+    secret: String,
+    attempts: u32,
+    retry_delay: Duration,
+    clock: Clock,
+}
+
+/// The headers which authenticate a request.
+#[derive(Debug, PartialEq, Eq)]
+struct Signed {
+    date: String,
+    md5: String,
+    authorization: String,
+}
+
+impl SolisClient {
+    pub fn new(http: Client, config: &Solis) -> Self {
+        SolisClient {
+            http,
+            api: config.api.clone(),
+            key: config.key.clone(),
+            secret: config.secret.clone(),
+            attempts: config.attempts,
+            retry_delay: Duration::from_millis(config.retry_delay_ms),
+            clock: Utc::now,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn with_clock(mut self, clock: Clock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub async fn inverters(&self) -> Result<Vec<InverterLite>> {
+        self.fetch_all("/v1/api/inverterList", &json!({})).await
+    }
+
+    pub async fn inverter_detail(&self, id: &str) -> Result<InverterDetail> {
+        self.call("/v1/api/inverterDetail", &json!({ "id": id }))
+            .await
+    }
+
+    pub async fn stations(&self) -> Result<Vec<Station>> {
+        self.fetch_all("/v1/api/userStationList", &json!({})).await
+    }
+
+    /// Alarms raised between `from` and `to`, cleared or not.
+    pub async fn alarms(&self, from: NaiveDate, to: NaiveDate) -> Result<Vec<Alarm>> {
+        self.fetch_all(
+            "/v1/api/alarmList",
+            &json!({
+                "alarmBeginTime": from.to_string(),
+                "alarmEndTime": to.to_string(),
+            }),
+        )
+        .await
+    }
+
+    /// Every record for `day`, UTC; `money` is the station's currency.
+    pub async fn inverter_day(
+        &self,
+        id: &str,
+        money: &str,
+        day: NaiveDate,
+    ) -> Result<Vec<InverterDetail>> {
+        self.call(
+            "/v1/api/inverterDay",
+            &json!({
+                "id": id,
+                "money": money,
+                "time": day.to_string(),
+                "timeZone": 0,
+            }),
+        )
+        .await
+    }
+
+    /// Daily totals for a month.
+    pub async fn inverter_month(
+        &self,
+        id: &str,
+        money: &str,
+        year: i32,
+        month: u32,
+    ) -> Result<Vec<Total>> {
+        self.call(
+            "/v1/api/inverterMonth",
+            &json!({
+                "id": id,
+                "money": money,
+                "month": format!("{year}-{month:02}"),
+            }),
+        )
+        .await
+    }
+
+    /// Monthly totals for a year.
+    pub async fn inverter_year(&self, id: &str, money: &str, year: i32) -> Result<Vec<Total>> {
+        self.call(
+            "/v1/api/inverterYear",
+            &json!({
+                "id": id,
+                "money": money,
+                "year": year.to_string(),
+            }),
+        )
+        .await
+    }
+
+    /// The current value of a setting, by command id.
+    pub async fn read_setting(&self, sn: &str, cid: u32) -> Result<String> {
+        let setting: Setting = self
+            .call("/v2/api/atRead", &json!({ "inverterSn": sn, "cid": cid }))
+            .await?;
+        Ok(setting.msg)
+    }
+
+    /// Change a setting; the inverter may not have taken it when this returns.
+    ///
+    /// Sent once, as repeating it isn't safe; read the setting back to see whether it worked.
+    pub async fn control(&self, sn: &str, cid: u32, value: &str) -> Result<()> {
+        let body = serde_json::to_vec(&json!({
+            "inverterSn": sn,
+            "cid": cid,
+            "value": value,
+        }))?;
+        self.call_once::<Value>("/v2/api/control", body).await?;
+        Ok(())
+    }
+
+    /// Every record from a paged list endpoint, with any extra `filter` in the request.
+    async fn fetch_all<T: DeserializeOwned>(&self, path: &str, filter: &Value) -> Result<Vec<T>> {
+        // the documented maximum
+        const PAGE_SIZE: u32 = 100;
+
+        let mut ret = Vec::new();
+        let mut page_no = 1;
+        loop {
+            let mut body = json!({
+                "pageNo": page_no,
+                "pageSize": PAGE_SIZE,
+            });
+            if let (Some(body), Some(filter)) = (body.as_object_mut(), filter.as_object()) {
+                body.extend(filter.clone());
+            }
+            let page = self.call::<Listing<T>>(path, &body).await?.into_page();
+            let empty = page.records.is_empty();
+            ret.extend(page.records);
+            if empty || ret.len() >= page.total || page_no >= page.pages {
+                if ret.len() < page.total {
+                    warn!("{path}: only got {} of {} records", ret.len(), page.total);
+                }
+                return Ok(ret);
+            }
+            page_no += 1;
+        }
+    }
+
+    /// The `data` from calling `path`, retrying rate limiting and server errors.
+    async fn call<T: DeserializeOwned>(&self, path: &str, data: &impl Serialize) -> Result<T> {
+        let body = serde_json::to_vec(&data)?;
+        let mut delay = self.retry_delay;
+        let mut attempt = 1;
+        loop {
+            let err = match self.call_once(path, body.clone()).await {
+                Ok(data) => return Ok(data),
+                Err(e) => e,
+            };
+            let transient = match err.downcast_ref::<ApiError>() {
+                Some(e) => e.is_transient(),
+                // couldn't connect, or the connection dropped
+                None => err.is::<reqwest::Error>(),
+            };
+            if !transient {
+                return Err(err);
+            }
+            if attempt >= self.attempts {
+                return Err(err.context(format!("giving up after {attempt} attempts")));
+            }
+            warn!("{path}: attempt {attempt} failed, retrying in {delay:?}: {err:?}");
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+            attempt += 1;
+        }
+    }
+
+    async fn call_once<T: DeserializeOwned>(&self, path: &str, body: Vec<u8>) -> Result<T> {
+        let signed = self.sign(path, &body, (self.clock)())?;
+        let resp = self
+            .http
+            .post(format!("{}{path}", self.api))
+            .header("Content-Type", "application/json;charset=utf-8")
+            .header("Date", signed.date)
+            .header("Authorization", signed.authorization)
+            .header("Content-MD5", signed.md5)
+            .body(body)
+            .send()
+            .await?;
+        let status = resp.status();
+        let body = resp.bytes().await?;
+
+        match serde_json::from_slice::<Envelope>(&body) {
+            Ok(env) if status.is_success() && env.code == "0" && env.success != Some(false) => {}
+            Ok(env) => return Err(ApiError::classify(status, &env.code, &env.msg).into()),
+            Err(_) if !status.is_success() => {
+                let text = String::from_utf8_lossy(&body);
+                return Err(ApiError::classify(status, "", &text).into());
+            }
+            Err(e) => return Err(anyhow!(e).context("parsing response")),
+        }
+        let resp = serde_json::from_slice::<Resp<T>>(&body).context("parsing response data")?;
+        Ok(resp.data)
+    }
+
+    fn sign(&self, path: &str, body: &[u8], now: DateTime<Utc>) -> Result<Signed> {
+        let md5 = b64.encode(md5::compute(body).0);
+        // the documented format; `+0000` is accepted too, but this is what the examples use
+        let date = now.format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+        let param = format!("POST\n{md5}\napplication/json\n{date}\n{path}");
+        let mut mac = HmacSha1::new_from_slice(self.secret.as_bytes())?;
+        mac.update(param.as_bytes());
+        let signature = b64.encode(mac.finalize().into_bytes());
+        Ok(Signed {
+            date,
+            md5,
+            authorization: format!("API {}:{signature}", self.key),
+        })
+    }
+}
+
+/// Every response is wrapped in this; see `call_once` for the rest of it.
+#[derive(Deserialize)]
+pub struct Resp<T> {
+    pub data: T,
+}
+
+/// The `data` from `atRead`.
+#[derive(Deserialize)]
+struct Setting {
+    msg: String,
+}
+
+/// The outside of a response, successful or not. Errors from the gateway, rather than
+/// the API itself, are capitalised.
+#[derive(Deserialize)]
+struct Envelope {
+    #[serde(
+        default,
+        alias = "Code",
+        deserialize_with = "deserialize_string_from_number"
+    )]
+    code: String,
+    #[serde(default, alias = "Msg", alias = "Message")]
+    msg: String,
+    #[serde(alias = "Success")]
+    success: Option<bool>,
+}
+
+/// The `data` of the list endpoints; `alarmList` doesn't wrap it in `page`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum Listing<T> {
+    Wrapped { page: Pager<T> },
+    Bare(Pager<T>),
+}
+
+impl<T> Listing<T> {
+    pub fn into_page(self) -> Pager<T> {
+        match self {
+            Listing::Wrapped { page } => page,
+            Listing::Bare(page) => page,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Pager<T> {
+    pub records: Vec<T>,
+    total: usize,
+    pages: u32,
+    // incomplete
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use chrono::{DateTime, TimeZone, Utc};
+    use reqwest::Client;
+    use serde_json::{json, Value};
+
+    use super::{Signed, SolisClient};
+    use crate::soliscloud::error::ApiError;
+    use crate::testing::stand_in;
+
+    fn client(api: String) -> SolisClient {
+        let config = crate::config::Solis {
+            api,
+            key: "123".to_string(),
+            secret: "sekrit".to_string(),
+            interval_secs: 60,
+            rediscover_secs: 60,
+            battery_kwh: None,
+            attempts: 2,
+            retry_delay_ms: 1,
+            allow_control: false,
+            readback_timeout_ms: 0,
+        };
+        SolisClient::new(Client::new(), &config).with_clock(then)
+    }
+
+    fn then() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2023, 10, 1, 12, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn test_sign() -> Result<()> {
+        let c = client(String::new());
+        assert_eq!(
+            c.sign("/v1/api/inverterDetail", br#"{"id":"1234567890"}"#, then())?,
+            Signed {
+                date: "Sun, 01 Oct 2023 12:00:00 GMT".to_string(),
+                md5: "kYlyk8EKk4yU8WzhgqM3Xg==".to_string(),
+                authorization: "API 123:OUPUyj2hwmoobWidlRpRDcIB9rY=".to_string(),
+            }
+        );
+        assert_eq!(
+            c.sign("/v1/api/userStationList", b"{}", then())?,
+            Signed {
+                date: "Sun, 01 Oct 2023 12:00:00 GMT".to_string(),
+                md5: "mZFLkyvTelC5g8XnyQrpOw==".to_string(),
+                authorization: "API 123:J6T1c1p1wjOOwfFVIDJhXFPuYEM=".to_string(),
+            }
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_call() -> Result<()> {
+        let ok = r#"{"success":true,"code":"0","msg":"success","data":{"id":"1"}}"#;
+        let (url, rx) = stand_in(&[(429, ""), (200, ok)])?;
+        let data: Value = client(url).call("/v1/api/x", &json!({})).await?;
+        assert_eq!(data, json!({"id": "1"}));
+        let (headers, body) = rx.recv()?;
+        let headers = headers.to_ascii_lowercase();
+        assert!(headers.starts_with("post /v1/api/x "));
+        assert!(headers.contains("date: sun, 01 oct 2023 12:00:00 gmt"));
+        assert!(headers.contains("authorization: api 123:"));
+        assert_eq!(body, "{}");
+        assert_eq!(rx.iter().count(), 1);
+
+        let gone = r#"{"success":false,"code":"1","msg":"Inverter does not exist","data":null}"#;
+        let (url, rx) = stand_in(&[(200, gone)])?;
+        let err = client(url).inverter_detail("1").await.unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&ApiError::NotFound));
+        assert_eq!(rx.iter().count(), 1);
+
+        let (url, rx) = stand_in(&[(403, r#"{"Success":false,"Code":"403"}"#)])?;
+        let err = client(url).stations().await.unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&ApiError::BadSignature));
+        assert_eq!(rx.iter().count(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fetch_all() -> Result<()> {
+        let page = |id: &str| {
+            format!(
+                r#"{{"success":true,"code":"0","msg":"success","data":{{"page":{{
+                    "records":[{{"id":"{id}","sn":"SN{id}"}}],"total":2,"pages":2}}}}}}"#
+            )
+        };
+        let (url, rx) = stand_in(&[(200, &page("1")), (200, &page("2"))])?;
+        let inverters = client(url).inverters().await?;
+        assert_eq!(
+            inverters.iter().map(|i| i.id.as_str()).collect::<Vec<_>>(),
+            ["1", "2"]
+        );
+        let bodies = rx.iter().map(|(_, body)| body).collect::<Vec<_>>();
+        assert_eq!(
+            bodies,
+            [
+                r#"{"pageNo":1,"pageSize":100}"#,
+                r#"{"pageNo":2,"pageSize":100}"#
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,301 @@
+//! Timed charge and discharge, `disport-data slots [set VALUE [--dry-run]]`.
+//!
+//! These are read with `atRead` and written with `control`, as command 103, whose value
+//! is three slots of `charge_a,discharge_a,HH:MM-HH:MM,HH:MM-HH:MM` (the charge window,
+//! then the discharge window) joined with commas; an unused window is `00:00-00:00`.
+//! Nothing is written unless `allow_control` is set for the account.
+//!
+//! A change can take a while to reach the inverter, so it's read back until it matches or
+//! `readback_timeout_ms` passes.
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{anyhow, ensure, Context, Result};
+use chrono::NaiveTime;
+use log::{debug, info};
+use tokio::time::Instant;
+
+use super::Service;
+
+/// The command id of the timed charge/discharge settings.
+const TIMED_CHARGE: u32 = 103;
+
+/// The most current, in amps, the app allows for a charge or discharge slot.
+const MAX_CURRENT_A: u32 = 100;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Window {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Slot {
+    pub charge_a: u32,
+    pub discharge_a: u32,
+    pub charge: Window,
+    pub discharge: Window,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Slots(pub [Slot; 3]);
+
+impl FromStr for Window {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (start, end) = s
+            .split_once('-')
+            .ok_or_else(|| anyhow!("expected HH:MM-HH:MM, not {s:?}"))?;
+        let time = |t: &str| {
+            NaiveTime::parse_from_str(t.trim(), "%H:%M")
+                .with_context(|| anyhow!("parsing {t:?} as HH:MM"))
+        };
+        Ok(Window {
+            start: time(start)?,
+            end: time(end)?,
+        })
+    }
+}
+
+impl fmt::Display for Window {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}-{}",
+            self.start.format("%H:%M"),
+            self.end.format("%H:%M")
+        )
+    }
+}
+
+impl FromStr for Slots {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let fields = s.split(',').map(str::trim).collect::<Vec<_>>();
+        ensure!(
+            fields.len() == 12,
+            "expected 12 comma-separated fields, not {}: {s:?}",
+            fields.len()
+        );
+        let current = |f: &str| -> Result<u32> {
+            let a = f
+                .parse::<u32>()
+                .with_context(|| anyhow!("parsing {f:?} as a current"))?;
+            ensure!(
+                a <= MAX_CURRENT_A,
+                "{a}A is over the {MAX_CURRENT_A}A limit"
+            );
+            Ok(a)
+        };
+        let mut slots = Vec::with_capacity(3);
+        for f in fields.chunks(4) {
+            slots.push(Slot {
+                charge_a: current(f[0])?,
+                discharge_a: current(f[1])?,
+                charge: f[2].parse()?,
+                discharge: f[3].parse()?,
+            });
+        }
+        Ok(Slots(slots.try_into().expect("three chunks")))
+    }
+}
+
+impl fmt::Display for Slots {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, slot) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            write!(
+                f,
+                "{},{},{},{}",
+                slot.charge_a, slot.discharge_a, slot.charge, slot.discharge
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// The current slots of every inverter, by serial number.
+pub async fn read(solis: &Service) -> Result<Vec<(String, Slots)>> {
+    let mut ret = Vec::with_capacity(solis.inverters.len());
+    for inverter in &solis.inverters {
+        ret.push((inverter.sn.clone(), read_one(solis, &inverter.sn).await?));
+    }
+    Ok(ret)
+}
+
+async fn read_one(solis: &Service, sn: &str) -> Result<Slots> {
+    let value = solis
+        .client
+        .read_setting(sn, TIMED_CHARGE)
+        .await
+        .with_context(|| anyhow!("reading slots from {sn}"))?;
+    value
+        .parse()
+        .with_context(|| anyhow!("parsing slots from {sn}"))
+}
+
+/// Set `slots` on every inverter which doesn't already have them, and check they stuck.
+pub async fn set(solis: &Service, slots: &Slots, dry_run: bool) -> Result<()> {
+    ensure!(
+        dry_run || solis.config.allow_control,
+        "changing settings needs `allow_control = true` for this account"
+    );
+    for slot in &slots.0 {
+        for a in [slot.charge_a, slot.discharge_a] {
+            ensure!(
+                a <= MAX_CURRENT_A,
+                "{a}A is over the {MAX_CURRENT_A}A limit"
+            );
+        }
+    }
+    for inverter in &solis.inverters {
+        let sn = &inverter.sn;
+        let current = read_one(solis, sn).await?;
+        if current == *slots {
+            info!("{sn}: already {slots}");
+            continue;
+        }
+        if dry_run {
+            info!("{sn}: would change {current} to {slots}");
+            continue;
+        }
+        info!("{sn}: changing {current} to {slots}");
+        solis
+            .client
+            .control(sn, TIMED_CHARGE, &slots.to_string())
+            .await
+            .with_context(|| anyhow!("setting slots on {sn}"))?;
+        read_back(solis, sn, slots).await?;
+    }
+    Ok(())
+}
+
+/// Wait for the inverter to report `slots`, giving up after `readback_timeout_ms`.
+async fn read_back(solis: &Service, sn: &str, slots: &Slots) -> Result<()> {
+    let every = Duration::from_millis(solis.config.retry_delay_ms);
+    let deadline = Instant::now() + Duration::from_millis(solis.config.readback_timeout_ms);
+    loop {
+        tokio::time::sleep(every).await;
+        let now = read_one(solis, sn).await?;
+        if now == *slots {
+            return Ok(());
+        }
+        ensure!(
+            Instant::now() < deadline,
+            "{sn}: still reads back {now}, not {slots}"
+        );
+        debug!("{sn}: not changed yet");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use chrono::NaiveTime;
+
+    use super::*;
+    use crate::soliscloud::InverterLite;
+    use crate::testing::stand_in;
+
+    const CHEAP: &str = "50,50,02:00-05:00,16:00-19:00,0,0,00:00-00:00,00:00-00:00,\
+                         0,0,00:00-00:00,00:00-00:00";
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        let slots: Slots = CHEAP.parse()?;
+        let hm = |h, m| NaiveTime::from_hms_opt(h, m, 0).unwrap();
+        assert_eq!(slots.0[0].charge_a, 50);
+        assert_eq!(
+            slots.0[0].discharge,
+            Window {
+                start: hm(16, 0),
+                end: hm(19, 0)
+            }
+        );
+        assert_eq!(slots.to_string(), CHEAP);
+        assert!("50,50,02:00-05:00".parse::<Slots>().is_err());
+        assert!(CHEAP.replace("02:00", "2am").parse::<Slots>().is_err());
+        assert!(CHEAP.replacen("50", "101", 1).parse::<Slots>().is_err());
+        Ok(())
+    }
+
+    fn service(api: String, allow_control: bool, readback_timeout_ms: u64) -> Service {
+        let config = crate::config::Solis {
+            api,
+            key: "123".to_string(),
+            secret: "sekrit".to_string(),
+            interval_secs: 60,
+            rediscover_secs: 60,
+            battery_kwh: None,
+            attempts: 2,
+            retry_delay_ms: 1,
+            allow_control,
+            readback_timeout_ms,
+        };
+        let mut solis = Service::new(&reqwest::Client::new(), config);
+        solis.inverters = vec![InverterLite {
+            id: "1".to_string(),
+            sn: "SN1".to_string(),
+        }];
+        solis
+    }
+
+    fn reply(value: &str) -> String {
+        format!(r#"{{"success":true,"code":"0","msg":"success","data":{{"msg":"{value}"}}}}"#)
+    }
+
+    #[tokio::test]
+    async fn test_set() -> Result<()> {
+        let old = CHEAP.replace("02:00-05:00", "00:00-00:00");
+        let slots: Slots = CHEAP.parse()?;
+
+        let (url, rx) = stand_in(&[(200, &reply(&old))])?;
+        set(&service(url, false, 1_000), &slots, true).await?;
+        assert_eq!(rx.iter().count(), 1);
+
+        let (url, _) = stand_in(&[])?;
+        assert!(set(&service(url, false, 1_000), &slots, false)
+            .await
+            .is_err());
+
+        let ok = r#"{"success":true,"code":"0","msg":"success","data":null}"#;
+        // the change takes a poll to show up
+        let (url, rx) = stand_in(&[
+            (200, &reply(&old)),
+            (200, ok),
+            (200, &reply(&old)),
+            (200, &reply(CHEAP)),
+        ])?;
+        set(&service(url, true, 1_000), &slots, false).await?;
+        let requests = rx.iter().collect::<Vec<_>>();
+        assert_eq!(requests.len(), 4);
+        assert!(requests[1].0.starts_with("POST /v2/api/control "));
+        assert_eq!(
+            requests[1].1,
+            format!(r#"{{"cid":103,"inverterSn":"SN1","value":"{CHEAP}"}}"#)
+        );
+
+        let (url, _) = stand_in(&[(200, &reply(&old)), (200, ok), (200, &reply(&old))])?;
+        assert!(set(&service(url, true, 0), &slots, false).await.is_err());
+
+        // a failed change isn't repeated, even if the error looks transient
+        let (url, rx) = stand_in(&[(200, &reply(&old)), (500, "")])?;
+        assert!(set(&service(url, true, 1_000), &slots, false)
+            .await
+            .is_err());
+        assert_eq!(rx.iter().count(), 2);
+
+        let mut big = slots;
+        big.0[1].discharge_a = 200;
+        let (url, rx) = stand_in(&[])?;
+        assert!(set(&service(url, true, 1_000), &big, false).await.is_err());
+        assert_eq!(rx.iter().count(), 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,463 @@
+//! The `data` of `/v1/api/inverterDetail`.
+//!
+//! The endpoint returns a few hundred fields, most of them with their unit in a sibling
+//! `fooStr` field, and the units vary between calls (`kWh` one minute, `MWh` the next).
+//! The fields we understand are pulled out into typed quantities here; everything else
+//! is kept in `other`, so it's obvious what isn't mapped.
+
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use serde_json::Value;
+
+use super::unit::{convert, Unit};
+
+/// `iPv1` .. `iPv32` etc.
+const MAX_STRINGS: usize = 32;
+
+/// A value which comes with a unit, normalised on the way in.
+pub trait Measure: Sized {
+    fn from_unit(value: f64, unit: &str) -> Result<Self>;
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Energy(f64);
+
+impl Energy {
+    pub fn kwh(self) -> f64 {
+        self.0
+    }
+}
+
+impl Measure for Energy {
+    fn from_unit(value: f64, unit: &str) -> Result<Self> {
+        convert(value, unit, Unit::KilowattHour).map(Energy)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Power(f64);
+
+impl Power {
+    pub fn w(self) -> f64 {
+        self.0
+    }
+}
+
+impl Measure for Power {
+    fn from_unit(value: f64, unit: &str) -> Result<Self> {
+        convert(value, unit, Unit::Watt).map(Power)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Voltage(f64);
+
+impl Voltage {
+    pub fn v(self) -> f64 {
+        self.0
+    }
+}
+
+impl Measure for Voltage {
+    fn from_unit(value: f64, unit: &str) -> Result<Self> {
+        convert(value, unit, Unit::Volt).map(Voltage)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Current(f64);
+
+impl Current {
+    pub fn a(self) -> f64 {
+        self.0
+    }
+}
+
+impl Measure for Current {
+    fn from_unit(value: f64, unit: &str) -> Result<Self> {
+        convert(value, unit, Unit::Ampere).map(Current)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Temperature(f64);
+
+impl Temperature {
+    pub fn c(self) -> f64 {
+        self.0
+    }
+}
+
+impl Measure for Temperature {
+    fn from_unit(value: f64, unit: &str) -> Result<Self> {
+        convert(value, unit, Unit::Celsius).map(Temperature)
+    }
+}
+
+/// `fooTodayEnergy`, `fooTotalEnergy`, .. for one kind of energy.
+#[derive(Default, Debug)]
+pub struct Periods {
+    pub total: Option<Energy>,
+    pub year: Option<Energy>,
+    pub month: Option<Energy>,
+    pub yesterday: Option<Energy>,
+    pub today: Option<Energy>,
+    /// `fooEnergy`, with no period; seems to match `today`
+    pub unqualified: Option<Energy>,
+}
+
+impl Periods {
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, Energy)> {
+        [
+            ("total", self.total),
+            ("year", self.year),
+            ("month", self.month),
+            ("yesterday", self.yesterday),
+            ("today", self.today),
+            ("", self.unqualified),
+        ]
+        .into_iter()
+        .filter_map(|(period, e)| Some((period, e?)))
+    }
+}
+
+/// One DC input.
+#[derive(Default, Debug)]
+pub struct PvString {
+    pub voltage: Option<Voltage>,
+    pub current: Option<Current>,
+    pub power: Option<Power>,
+}
+
+/// One AC phase.
+#[derive(Default, Debug)]
+pub struct Phase {
+    pub ac_voltage: Option<Voltage>,
+    pub ac_current: Option<Current>,
+    /// `uA` etc.; slightly different from `uAc1`, the grid side of the meter?
+    pub grid_voltage: Option<Voltage>,
+    pub grid_current: Option<Current>,
+    pub power_factor: Option<f64>,
+    /// var; never seen with a unit
+    pub reactive_power: Option<f64>,
+    /// VA, "looked" power upstream; never seen with a unit
+    pub apparent_power: Option<f64>,
+    pub bypass_voltage: Option<Voltage>,
+    pub bypass_current: Option<Current>,
+}
+
+/// The battery, as seen by its BMS and by the inverter.
+#[derive(Default, Debug)]
+pub struct Battery {
+    /// %, `batteryCapacitySoc`
+    pub soc: Option<f64>,
+    /// %, `batteryHealthSoh`
+    pub soh: Option<f64>,
+    pub voltage: Option<Voltage>,
+    /// `bstteryCurrent` (sic)
+    pub current: Option<Current>,
+    pub bms_power: Option<Power>,
+    /// `storageBattery*`, measured by the inverter rather than reported by the BMS
+    pub inverter_voltage: Option<Voltage>,
+    pub inverter_current: Option<Current>,
+    /// the BMS's current limits, `batteryChargingCurrent` and `batteryDischargeLimiting`
+    pub charge_limit: Option<Current>,
+    pub discharge_limit: Option<Current>,
+    /// limits set on the inverter, `batteryCMaxiSet` and `batteryDMaxiSet`
+    pub max_charge_set: Option<Current>,
+    pub max_discharge_set: Option<Current>,
+    /// for forced charging, `batteryCDISet`
+    pub force_current_set: Option<Current>,
+    /// `batteryFcvSet`, `batteryAcvSet`, `batteryOvpSet`, `batteryUvpSet`
+    pub float_voltage_set: Option<Voltage>,
+    pub absorption_voltage_set: Option<Voltage>,
+    pub over_voltage_set: Option<Voltage>,
+    pub under_voltage_set: Option<Voltage>,
+}
+
+/// Operating state and fault flags, as the codes SolisCloud sends.
+#[derive(Default, Debug)]
+pub struct Status {
+    /// `state`: online, offline, alarm
+    pub state: Option<u64>,
+    /// `currentState`: waiting, generating, ..
+    pub current_state: Option<u64>,
+    /// `stateExceptionFlag`
+    pub exception: Option<u64>,
+    pub bms_state: Option<u64>,
+    /// bit fields, by their name in metrics; zero when all is well
+    pub faults: Vec<(&'static str, u64)>,
+}
+
+#[derive(Default, Debug, Deserialize)]
+#[serde(try_from = "HashMap<String, Value>")]
+pub struct InverterDetail {
+    pub data_timestamp: Option<Value>,
+
+    pub backup: Periods,
+    pub grid_purchased: Periods,
+    pub grid_sell: Periods,
+    pub home_grid: Periods,
+    pub home_load: Periods,
+    pub generator: Periods,
+    pub battery_charge: Periods,
+    pub battery_discharge: Periods,
+
+    pub ac_power: Option<Power>,
+    pub battery_power: Option<Power>,
+    pub bypass_load_power: Option<Power>,
+    pub family_load_power: Option<Power>,
+    pub generator_power: Option<Power>,
+    pub total_load_power: Option<Power>,
+    pub total_power: Option<Power>,
+    // not sure what any of these are, but they have units
+    // export power management?
+    pub epm_power: Option<Power>,
+    pub epm_set_power: Option<Power>,
+    pub sum_power: Option<Power>,
+    pub sum_cal_power: Option<Power>,
+
+    pub inverter_temperature: Option<Temperature>,
+    pub battery: Battery,
+
+    pub status: Status,
+
+    /// only those the inverter says it has, from 1
+    pub strings: Vec<PvString>,
+    /// only those the inverter says it has, `a`, `b`, `c`
+    pub phases: Vec<Phase>,
+
+    /// everything not understood above, less anything identifying
+    pub other: HashMap<String, Value>,
+}
+
+impl TryFrom<HashMap<String, Value>> for InverterDetail {
+    type Error = anyhow::Error;
+
+    fn try_from(fields: HashMap<String, Value>) -> Result<Self> {
+        let mut f = Fields(fields);
+        // as blanked by `tests/ref/soliscloud/redact.jq`: whose it is, and where
+        for secret in [
+            "sn",
+            "sno",
+            "id",
+            "inverterId",
+            "userId",
+            "stationId",
+            "stationName",
+            "collectorId",
+            "collectorsn",
+            "ammeterId",
+        ] {
+            f.0.remove(secret);
+        }
+
+        let mut d = InverterDetail {
+            data_timestamp: f.0.remove("dataTimestamp"),
+            ..Default::default()
+        };
+
+        for (class, periods) in [
+            ("backup", &mut d.backup),
+            ("gridPurchased", &mut d.grid_purchased),
+            ("gridSell", &mut d.grid_sell),
+            ("homeGrid", &mut d.home_grid),
+            ("homeLoad", &mut d.home_load),
+            ("generator", &mut d.generator),
+        ] {
+            *periods = f.periods(|period| format!("{class}{period}Energy"))?;
+        }
+        d.battery_charge = f.periods(|period| format!("battery{period}ChargeEnergy"))?;
+        d.battery_discharge = f.periods(|period| format!("battery{period}DischargeEnergy"))?;
+
+        d.ac_power = f.take("pac")?;
+        d.battery_power = f.take("batteryPower")?;
+        d.bypass_load_power = f.take("bypassLoadPower")?;
+        d.family_load_power = f.take("familyLoadPower")?;
+        d.generator_power = f.take("generatorPower")?;
+        d.total_load_power = f.take("totalLoadPower")?;
+        d.total_power = f.take("powTotal")?;
+        d.epm_power = f.take("pEpm")?;
+        d.epm_set_power = f.take("pEpmSet")?;
+        d.sum_power = f.take("psum")?;
+        d.sum_cal_power = f.take("psumCal")?;
+
+        d.inverter_temperature = f.take("inverterTemperature")?;
+
+        d.battery = Battery {
+            soc: f.number("batteryCapacitySoc")?,
+            soh: f.number("batteryHealthSoh")?,
+            voltage: f.take("batteryVoltage")?,
+            current: match f.take("bstteryCurrent")? {
+                Some(current) => Some(current),
+                // in case they ever fix the spelling
+                None => f.take("batteryCurrent")?,
+            },
+            bms_power: f.take("batteryPowerBms")?,
+            inverter_voltage: f.take("storageBatteryVoltage")?,
+            inverter_current: f.take("storageBatteryCurrent")?,
+            charge_limit: f.take("batteryChargingCurrent")?,
+            discharge_limit: f.take("batteryDischargeLimiting")?,
+            max_charge_set: f.take_or("batteryCMaxiSet", "A")?,
+            max_discharge_set: f.take_or("batteryDMaxiSet", "A")?,
+            force_current_set: f.take_or("batteryCDISet", "A")?,
+            float_voltage_set: f.take_or("batteryFcvSet", "V")?,
+            absorption_voltage_set: f.take_or("batteryAcvSet", "V")?,
+            over_voltage_set: f.take_or("batteryOvpSet", "V")?,
+            under_voltage_set: f.take_or("batteryUvpSet", "V")?,
+        };
+
+        d.status = Status {
+            state: f.code("state")?,
+            current_state: f.code("currentState")?,
+            exception: f.code("stateExceptionFlag")?,
+            bms_state: f.code("bmsState")?,
+            faults: Vec::new(),
+        };
+        for (k, name) in [
+            ("alarmState", "alarm_state"),
+            ("batteryAlarm", "battery_alarm"),
+            ("batteryFailureInformation01", "battery_failure_01"),
+            ("batteryFailureInformation02", "battery_failure_02"),
+            ("generatorWarning", "generator_warning"),
+            ("warningInfoData", "warning_info"),
+        ] {
+            if let Some(bits) = f.bits(k)? {
+                d.status.faults.push((name, bits));
+            }
+        }
+
+        // the highest string index, counting from zero; `1` on a two string inverter
+        let strings = match f.0.get("dcInputtype").and_then(|v| v.as_u64()) {
+            Some(n) => n as usize + 1,
+            None => MAX_STRINGS,
+        };
+        for i in 1..=MAX_STRINGS {
+            let string = PvString {
+                voltage: f.take_or(&format!("uPv{i}"), "V")?,
+                current: f.take_or(&format!("iPv{i}"), "A")?,
+                power: f.take_or(&format!("pow{i}"), "W")?,
+            };
+            // always zero on the inverters we've seen, and with no units
+            for k in ["mpptIpv", "mpptPow", "mpptUpv"] {
+                f.0.remove(&format!("{k}{i}"));
+            }
+            if i <= strings {
+                d.strings.push(string);
+            }
+        }
+
+        let phases = match f.0.get("acOutputType").and_then(|v| v.as_u64()) {
+            Some(0) => 1,
+            _ => 3,
+        };
+        for (i, (lower, upper, suffix)) in [("a", "A", ""), ("b", "B", "B"), ("c", "C", "C")]
+            .into_iter()
+            .enumerate()
+        {
+            let phase = Phase {
+                ac_voltage: f.take_or(&format!("uAc{}", i + 1), "V")?,
+                ac_current: f.take_or(&format!("iAc{}", i + 1), "A")?,
+                grid_voltage: f.take_or(&format!("u{upper}"), "V")?,
+                grid_current: f.take_or(&format!("i{upper}"), "A")?,
+                power_factor: f.number(&format!("{lower}PhasePowerFactor"))?,
+                reactive_power: f.number(&format!("{lower}ReactivePower"))?,
+                apparent_power: f.number(&format!("{lower}LookedPower"))?,
+                bypass_voltage: f.take_or(&format!("bypassAcVoltage{suffix}"), "V")?,
+                bypass_current: f.take_or(&format!("bypassAcCurrent{suffix}"), "A")?,
+            };
+            if i < phases {
+                d.phases.push(phase);
+            }
+        }
+
+        d.other = f.0;
+        Ok(d)
+    }
+}
+
+/// The fields not yet claimed.
+struct Fields(HashMap<String, Value>);
+
+impl Fields {
+    /// Remove a plain number; `None` if it's missing or null.
+    fn number(&mut self, k: &str) -> Result<Option<f64>> {
+        match self.0.remove(k) {
+            None | Some(Value::Null) => Ok(None),
+            Some(v) => v
+                .as_f64()
+                .map(Some)
+                .ok_or_else(|| anyhow!("value for {k} not a number: {v:?}")),
+        }
+    }
+
+    /// Remove an enumerated value, which may arrive as a number or a string.
+    fn code(&mut self, k: &str) -> Result<Option<u64>> {
+        match self.0.remove(k) {
+            None | Some(Value::Null) => Ok(None),
+            Some(Value::String(s)) => s
+                .parse()
+                .map(Some)
+                .with_context(|| anyhow!("code for {k} not a number: {s:?}")),
+            Some(v) => v
+                .as_u64()
+                .map(Some)
+                .ok_or_else(|| anyhow!("code for {k} not a number: {v:?}")),
+        }
+    }
+
+    /// Remove a bit field; hex in strings.
+    fn bits(&mut self, k: &str) -> Result<Option<u64>> {
+        match self.0.remove(k) {
+            None | Some(Value::Null) => Ok(None),
+            Some(Value::String(s)) => u64::from_str_radix(s.trim_start_matches("0x"), 16)
+                .map(Some)
+                .with_context(|| anyhow!("bits for {k} not hex: {s:?}")),
+            Some(v) => v
+                .as_u64()
+                .map(Some)
+                .ok_or_else(|| anyhow!("bits for {k} not a number: {v:?}")),
+        }
+    }
+
+    /// Remove a value, and its `Str` or `Unit`; left alone if there's no unit.
+    fn take<M: Measure>(&mut self, k: &str) -> Result<Option<M>> {
+        let unit = [format!("{k}Str"), format!("{k}Unit")]
+            .into_iter()
+            .find(|u| self.0.get(u).is_some_and(|v| v.is_string()));
+        let Some(unit) = unit else {
+            return Ok(None);
+        };
+        let unit = self.0.remove(&unit).expect("just found");
+        let unit = unit.as_str().expect("just checked");
+        self.measure(k, unit)
+    }
+
+    /// Remove a value, using `unit` if it doesn't come with one.
+    fn take_or<M: Measure>(&mut self, k: &str, unit: &str) -> Result<Option<M>> {
+        match self.0.remove(&format!("{k}Str")) {
+            Some(Value::String(got)) => self.measure(k, &got),
+            _ => self.measure(k, unit),
+        }
+    }
+
+    fn measure<M: Measure>(&mut self, k: &str, unit: &str) -> Result<Option<M>> {
+        self.number(k)?
+            .map(|v| M::from_unit(v, unit))
+            .transpose()
+            .with_context(|| anyhow!("processing {k:?}"))
+    }
+
+    fn periods(&mut self, key: impl Fn(&str) -> String) -> Result<Periods> {
+        Ok(Periods {
+            total: self.take(&key("Total"))?,
+            year: self.take(&key("Year"))?,
+            month: self.take(&key("Month"))?,
+            yesterday: self.take(&key("Yesterday"))?,
+            today: self.take(&key("Today"))?,
+            unqualified: self.take(&key(""))?,
+        })
+    }
+}
@@ -0,0 +1,160 @@
+//! What went wrong talking to SolisCloud, from the HTTP status and the response envelope.
+//!
+//! SolisCloud isn't consistent about where it reports problems: some come back as an
+//! HTTP error, some as a `200` with `success: false`. The envelope `code` is tried first,
+//! then the status, and only then whole words or phrases from `msg`.
+
+use std::fmt;
+
+use reqwest::StatusCode;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ApiError {
+    /// the key, secret or signature was rejected
+    BadSignature,
+    /// the `Date` header is too far from the server's clock
+    ClockSkew,
+    RateLimited,
+    /// e.g. an inverter id which no longer exists
+    NotFound,
+    Other {
+        status: StatusCode,
+        code: String,
+        msg: String,
+    },
+}
+
+/// Envelope codes with a known meaning; the gateway repeats the HTTP status in `code`.
+const CODES: &[(&str, ApiError)] = &[
+    ("401", ApiError::BadSignature),
+    ("403", ApiError::BadSignature),
+    ("404", ApiError::NotFound),
+    ("429", ApiError::RateLimited),
+];
+
+/// Phrases in `msg`, matched as whole words, for when the code and status say nothing.
+const PHRASES: &[(&str, ApiError)] = &[
+    ("sign", ApiError::BadSignature),
+    ("signature", ApiError::BadSignature),
+    ("unauthorized", ApiError::BadSignature),
+    ("authentication failed", ApiError::BadSignature),
+    ("time difference", ApiError::ClockSkew),
+    ("time diff", ApiError::ClockSkew),
+    ("clock", ApiError::ClockSkew),
+    ("too frequent", ApiError::RateLimited),
+    ("too frequently", ApiError::RateLimited),
+    ("rate limit", ApiError::RateLimited),
+    ("rate limited", ApiError::RateLimited),
+    ("not exist", ApiError::NotFound),
+    ("not found", ApiError::NotFound),
+];
+
+impl ApiError {
+    pub fn classify(status: StatusCode, code: &str, msg: &str) -> Self {
+        if let Some((_, e)) = CODES.iter().find(|(c, _)| *c == code) {
+            return e.clone();
+        }
+        match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => return ApiError::BadSignature,
+            StatusCode::NOT_FOUND => return ApiError::NotFound,
+            StatusCode::TOO_MANY_REQUESTS => return ApiError::RateLimited,
+            _ => (),
+        }
+        let words = msg
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|w| !w.is_empty())
+            .map(str::to_lowercase)
+            .collect::<Vec<_>>();
+        let mentions = |phrase: &str| {
+            let phrase = phrase.split(' ').collect::<Vec<_>>();
+            words.windows(phrase.len()).any(|w| w == phrase)
+        };
+        match PHRASES.iter().find(|(p, _)| mentions(p)) {
+            Some((_, e)) => e.clone(),
+            None => ApiError::Other {
+                status,
+                code: code.to_string(),
+                msg: msg.to_string(),
+            },
+        }
+    }
+
+    /// Whether the same request might work if it's tried again shortly.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            ApiError::RateLimited => true,
+            ApiError::Other { status, .. } => {
+                status.is_server_error() || *status == StatusCode::REQUEST_TIMEOUT
+            }
+            ApiError::BadSignature | ApiError::ClockSkew | ApiError::NotFound => false,
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::BadSignature => write!(f, "signature rejected; check the key and secret"),
+            ApiError::ClockSkew => write!(f, "request date rejected; is the clock right?"),
+            ApiError::RateLimited => write!(f, "rate limited"),
+            ApiError::NotFound => write!(f, "not found"),
+            ApiError::Other { status, code, msg } => {
+                write!(f, "failed with {status}, code {code:?}: {msg:?}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::StatusCode;
+
+    use super::ApiError;
+
+    #[test]
+    fn test_classify() {
+        let ok = StatusCode::OK;
+        assert_eq!(
+            ApiError::classify(StatusCode::FORBIDDEN, "", ""),
+            ApiError::BadSignature
+        );
+        assert_eq!(
+            ApiError::classify(ok, "403", "Forbidden"),
+            ApiError::BadSignature
+        );
+        assert_eq!(
+            ApiError::classify(ok, "1", "Request time difference too large"),
+            ApiError::ClockSkew
+        );
+        assert_eq!(
+            ApiError::classify(ok, "1", "Request too frequent"),
+            ApiError::RateLimited
+        );
+        assert_eq!(
+            ApiError::classify(ok, "1", "Inverter does not exist"),
+            ApiError::NotFound
+        );
+
+        // neither a timeout nor a word that merely contains "limit" or "sign" is specific
+        let timeout = ApiError::classify(StatusCode::REQUEST_TIMEOUT, "", "");
+        assert!(matches!(timeout, ApiError::Other { .. }));
+        assert!(timeout.is_transient());
+        for msg in [
+            "Power limit must be between 0 and 100",
+            "Unassigned inverter",
+            "Request timeout",
+        ] {
+            assert!(
+                matches!(ApiError::classify(ok, "1", msg), ApiError::Other { .. }),
+                "{msg}"
+            );
+        }
+
+        let other = ApiError::classify(StatusCode::BAD_GATEWAY, "", "");
+        assert!(matches!(other, ApiError::Other { .. }));
+        assert!(other.is_transient());
+        assert!(!ApiError::BadSignature.is_transient());
+    }
+}
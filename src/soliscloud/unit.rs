@@ -0,0 +1,172 @@
+//! The units SolisCloud puts in its `fooStr` / `fooUnit` fields.
+//!
+//! They change with the magnitude of the value (`kWh` becomes `MWh` as a total grows),
+//! so everything is normalised to one unit per quantity before it's emitted.
+
+use anyhow::{bail, ensure, Result};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Unit {
+    Watt,
+    KilowattHour,
+    KilowattPeak,
+    Volt,
+    Ampere,
+    VoltAmpere,
+    Var,
+    Hertz,
+    Celsius,
+    Percent,
+    Hour,
+}
+
+impl Unit {
+    /// The end of a metric name in this unit, e.g. `w` for `ac_power_w`.
+    pub fn suffix(self) -> &'static str {
+        match self {
+            Unit::Watt => "w",
+            Unit::KilowattHour => "kwh",
+            Unit::KilowattPeak => "kwp",
+            Unit::Volt => "v",
+            Unit::Ampere => "a",
+            Unit::VoltAmpere => "va",
+            Unit::Var => "var",
+            Unit::Hertz => "hz",
+            Unit::Celsius => "c",
+            Unit::Percent => "pct",
+            Unit::Hour => "h",
+        }
+    }
+}
+
+/// The canonical unit for `unit`, and what to multiply values by to get there.
+pub fn parse(unit: &str) -> Result<(f64, Unit)> {
+    let unit = unit.trim();
+    if let Some(base) = base(unit) {
+        return Ok(base);
+    }
+
+    let mut chars = unit.chars();
+    let scale = match chars.next() {
+        Some('m') => 0.001,
+        Some('k') => 1_000.,
+        Some('M') => 1_000_000.,
+        Some('G') => 1_000_000_000.,
+        _ => bail!("unknown unit: {unit:?}"),
+    };
+    match base(chars.as_str()) {
+        Some((mul, base)) if scaled(base) => Ok((mul * scale, base)),
+        _ => bail!("unknown unit: {unit:?}"),
+    }
+}
+
+/// `value`, in `unit`, converted to `want`.
+pub fn convert(value: f64, unit: &str, want: Unit) -> Result<f64> {
+    let (mul, got) = parse(unit)?;
+    ensure!(
+        got == want,
+        "expected {want:?}, not {unit:?} for value {value:?}"
+    );
+    Ok(value * mul)
+}
+
+fn base(unit: &str) -> Option<(f64, Unit)> {
+    Some(match unit {
+        "W" => (1., Unit::Watt),
+        "Wh" => (0.001, Unit::KilowattHour),
+        "Wp" => (0.001, Unit::KilowattPeak),
+        "V" => (1., Unit::Volt),
+        "A" => (1., Unit::Ampere),
+        "VA" => (1., Unit::VoltAmpere),
+        // `Var` in practice
+        "var" | "Var" | "VAr" | "VAR" => (1., Unit::Var),
+        "Hz" => (1., Unit::Hertz),
+        "℃" | "°C" | "C" => (1., Unit::Celsius),
+        "%" => (1., Unit::Percent),
+        "h" => (1., Unit::Hour),
+        _ => return None,
+    })
+}
+
+/// Whether `k`, `M`, etc. make sense in front of the unit.
+fn scaled(unit: Unit) -> bool {
+    !matches!(unit, Unit::Celsius | Unit::Percent | Unit::Hour)
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::{Context, Result};
+    use serde_json::Value;
+
+    use super::*;
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        for (unit, mul, want) in [
+            ("W", 1., Unit::Watt),
+            ("kW", 1_000., Unit::Watt),
+            ("MW", 1_000_000., Unit::Watt),
+            ("Wh", 0.001, Unit::KilowattHour),
+            ("kWh", 1., Unit::KilowattHour),
+            ("MWh", 1_000., Unit::KilowattHour),
+            ("GWh", 1_000_000., Unit::KilowattHour),
+            ("kWp", 1., Unit::KilowattPeak),
+            ("V", 1., Unit::Volt),
+            ("A", 1., Unit::Ampere),
+            ("VA", 1., Unit::VoltAmpere),
+            ("kVA", 1_000., Unit::VoltAmpere),
+            ("Var", 1., Unit::Var),
+            ("kvar", 1_000., Unit::Var),
+            ("Hz", 1., Unit::Hertz),
+            ("℃", 1., Unit::Celsius),
+            ("°C", 1., Unit::Celsius),
+            ("%", 1., Unit::Percent),
+            ("h", 1., Unit::Hour),
+        ] {
+            assert_eq!(parse(unit)?, (mul, want), "{unit}");
+        }
+        for bad in ["", "kh", "k%", "AFCI0", "2023-09-01", "UTC+00:00", "--"] {
+            assert!(parse(bad).is_err(), "{bad}");
+        }
+        assert!(convert(1., "kW", Unit::KilowattHour).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_fixtures() -> Result<()> {
+        // display versions of the value, rather than units; as are all the `fooTimeStr`
+        let not_units = ["afciTypeStr", "timeZoneStr"];
+
+        let mut seen = 0;
+        for fixture in [
+            include_str!("../../tests/ref/soliscloud/inverterDetail.json"),
+            include_str!("../../tests/ref/soliscloud/unitFlip.json"),
+            include_str!("../../tests/ref/soliscloud/2024-03-07T12-57-detail-gen.json"),
+            include_str!("../../tests/ref/soliscloud/2024-03-07T14-27-detail-gen.json"),
+        ] {
+            let detail: serde_json::Map<String, Value> = serde_json::from_str(fixture)?;
+            for (k, unit) in &detail {
+                let Some(field) = k.strip_suffix("Str").or_else(|| k.strip_suffix("Unit")) else {
+                    continue;
+                };
+                let (Some(unit), Some(Value::Number(_))) = (unit.as_str(), detail.get(field))
+                else {
+                    continue;
+                };
+                if k.contains("Time") || not_units.contains(&k.as_str()) {
+                    continue;
+                }
+                parse(unit).with_context(|| format!("{k}: {unit:?}"))?;
+                seen += 1;
+            }
+        }
+        assert!(seen > 500, "{seen}");
+
+        let flip: serde_json::Map<String, Value> =
+            serde_json::from_str(include_str!("../../tests/ref/soliscloud/unitFlip.json"))?;
+        let total = flip["homeLoadTotalEnergy"].as_f64().expect("number");
+        let unit = flip["homeLoadTotalEnergyStr"].as_str().expect("string");
+        assert_eq!(convert(total, unit, Unit::KilowattHour)?, 1377.);
+        Ok(())
+    }
+}
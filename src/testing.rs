@@ -0,0 +1,51 @@
+//! Helpers for tests which need something to talk to.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::mpsc;
+use std::thread;
+
+use anyhow::Result;
+
+/// An HTTP server answering one request per entry in `responses`, (status, body),
+/// reporting each (request line and headers, body) received.
+///
+/// Returns the base url, e.g. `http://127.0.0.1:1234`.
+pub fn stand_in(responses: &[(u16, &str)]) -> Result<(String, mpsc::Receiver<(String, String)>)> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let url = format!("http://{}", listener.local_addr()?);
+    let responses = responses
+        .iter()
+        .map(|(status, body)| (*status, body.to_string()))
+        .collect::<Vec<_>>();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for (status, reply) in responses {
+            let (mut stream, _) = listener.accept().expect("accept");
+            let mut reader = BufReader::new(stream.try_clone().expect("clone"));
+            let mut headers = String::new();
+            let mut len = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).expect("read");
+                if line == "\r\n" {
+                    break;
+                }
+                if let Some(v) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                    len = v.trim().parse().expect("length");
+                }
+                headers.push_str(&line);
+            }
+            let mut body = vec![0; len];
+            reader.read_exact(&mut body).expect("body");
+            write!(
+                stream,
+                "HTTP/1.1 {status} X\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{reply}",
+                reply.len()
+            )
+            .expect("write");
+            let _ = tx.send((headers, String::from_utf8(body).expect("utf-8")));
+        }
+    });
+    Ok((url, rx))
+}
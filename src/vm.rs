@@ -1,28 +1,95 @@
-use std::io::Write;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, ensure, Context, Result};
 use chrono::{DateTime, Utc};
+use log::{info, warn};
+use reqwest::{Client, StatusCode};
+use serde::Deserialize;
 use serde_json::json;
 
-pub struct FullName(serde_json::Map<String, serde_json::Value>);
+use crate::{config, prom};
+
+/// A series: its name, as `__name__`, and labels.
+///
+/// Names and label keys are made to fit the Prometheus data model: characters which
+/// aren't allowed become `_`, as does a leading digit.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct FullName(BTreeMap<String, String>);
 
 impl FullName {
     pub fn new(
         name: impl ToString,
         labels: impl IntoIterator<Item = (impl ToString, impl ToString)>,
-    ) -> Self {
-        let labels = labels.into_iter();
-        let mut map = serde_json::Map::with_capacity(labels.size_hint().1.unwrap_or(0) + 1);
+    ) -> Result<Self> {
+        let name = sanitise(&name.to_string(), true)
+            .with_context(|| anyhow!("metric name {:?}", name.to_string()))?;
+        // e.g. `soliscloud_soliscloud_ac_power_w`, from adding a prefix twice
+        let mut parts = name.split('_');
+        if let (Some(first), Some(second)) = (parts.next(), parts.next()) {
+            ensure!(
+                first.is_empty() || first != second,
+                "metric name {name:?} repeats its prefix"
+            );
+        }
+
+        let mut ret = FullName(BTreeMap::new());
+        ret.0.insert("__name__".to_string(), name);
         for (k, v) in labels {
-            let k = k.to_string();
-            assert_ne!(k, "__name__");
-            map.insert(k, json!(v.to_string()));
+            ret.add_label(k, v)?;
         }
+        Ok(ret)
+    }
+
+    pub fn plain(name: impl ToString) -> Result<Self> {
+        FullName::new(name, std::iter::empty::<(String, String)>())
+    }
+
+    /// Add, or replace, a label; any value is allowed.
+    pub fn add_label(&mut self, key: impl ToString, value: impl ToString) -> Result<()> {
+        let key = key.to_string();
+        let key = sanitise(&key, false).with_context(|| anyhow!("label {key:?}"))?;
+        ensure!(
+            !key.starts_with("__"),
+            "label {key:?} is reserved, starting with `__`"
+        );
+        self.0.insert(key, value.to_string());
+        Ok(())
+    }
+
+    pub fn name(&self) -> &str {
+        self.label("__name__").expect("always set in new")
+    }
 
-        map.insert("__name__".to_string(), json!(name.to_string()));
+    pub fn label(&self, key: &str) -> Option<&str> {
+        self.0.get(key).map(String::as_str)
+    }
 
-        FullName(map)
+    /// Every label except the name, in key order.
+    pub fn labels(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0
+            .iter()
+            .filter(|(k, _)| *k != "__name__")
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+}
+
+/// `[a-zA-Z_:][a-zA-Z0-9_:]*` for metric names; label keys can't have `:`.
+fn sanitise(s: &str, colons: bool) -> Result<String> {
+    ensure!(!s.is_empty(), "empty");
+    let mut ret = String::with_capacity(s.len() + 1);
+    if s.starts_with(|c: char| c.is_ascii_digit()) {
+        ret.push('_');
+    }
+    for c in s.chars() {
+        let ok = c.is_ascii_alphanumeric() || c == '_' || (colons && c == ':');
+        ret.push(if ok { c } else { '_' });
     }
+    Ok(ret)
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -42,21 +109,787 @@ impl Obs {
     pub fn now(value: f64) -> Self {
         Obs::new(value, Utc::now())
     }
+
+    pub fn value(&self) -> f64 {
+        self.value
+    }
+
+    pub fn when(&self) -> DateTime<Utc> {
+        DateTime::from_timestamp_millis(self.timestamp).expect("constructed from a DateTime")
+    }
+}
+
+/// Writes metrics out one series at a time, in some wire format.
+pub trait Writer: Sync {
+    fn write(&self, write: &mut dyn Write, name: &FullName, obs: &[Obs]) -> Result<()>;
+}
+
+/// VictoriaMetrics' JSON lines, as taken by `/api/v1/import`.
+pub struct VmJson;
+
+impl Writer for VmJson {
+    fn write(&self, write: &mut dyn Write, name: &FullName, obs: &[Obs]) -> Result<()> {
+        let values = obs.iter().map(|o| o.value).collect::<Vec<_>>();
+        let timestamps = obs.iter().map(|o| o.timestamp).collect::<Vec<_>>();
+
+        serde_json::to_writer(
+            &mut *write,
+            &json!({
+                "metric": name.0,
+                "values": values,
+                "timestamps": timestamps,
+            }),
+        )?;
+        write.write_all(b"\n")?;
+
+        Ok(())
+    }
+}
+
+/// InfluxDB line protocol: the name is the measurement, the labels are tags, and each
+/// observation is a `value` field with a millisecond timestamp.
+pub struct InfluxLine;
+
+impl Writer for InfluxLine {
+    fn write(&self, write: &mut dyn Write, name: &FullName, obs: &[Obs]) -> Result<()> {
+        let mut series = escape_influx(name.name(), &[',', ' ']);
+        for (k, v) in name.labels() {
+            // empty tags aren't allowed
+            if v.is_empty() {
+                continue;
+            }
+            series.push(',');
+            series.push_str(&escape_influx(k, &[',', '=', ' ']));
+            series.push('=');
+            series.push_str(&escape_influx(v, &[',', '=', ' ']));
+        }
+        // nor are NaN and infinities
+        for o in obs.iter().filter(|o| o.value.is_finite()) {
+            writeln!(write, "{series} value={} {}", o.value, o.timestamp)?;
+        }
+        Ok(())
+    }
+}
+
+fn escape_influx(s: &str, special: &[char]) -> String {
+    let mut ret = String::with_capacity(s.len());
+    for c in s.chars() {
+        if c == '\\' || special.contains(&c) {
+            ret.push('\\');
+        }
+        ret.push(c);
+    }
+    ret
+}
+
+/// Which `Writer` a file or stdout gets, as `format = "..."` in the config.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// `VmJson`
+    #[default]
+    Vm,
+    /// `InfluxLine`
+    Influx,
+}
+
+impl Format {
+    pub fn writer(self) -> &'static dyn Writer {
+        match self {
+            Format::Vm => &VmJson,
+            Format::Influx => &InfluxLine,
+        }
+    }
+}
+
+/// One line (or, for Influx, one run of lines) per series, in order of name.
+fn render(writer: &dyn Writer, produced: &[(FullName, Obs)]) -> Result<Vec<u8>> {
+    let mut series = BTreeMap::<&FullName, Vec<Obs>>::new();
+    for (name, obs) in produced {
+        series.entry(name).or_default().push(*obs);
+    }
+    let mut buf = Vec::with_capacity(4096);
+    for (name, obs) in series {
+        writer.write(&mut buf, name, &obs)?;
+    }
+    Ok(buf)
+}
+
+/// Somewhere to write metrics.
+pub enum Sink {
+    Stdout(Format),
+    File(File),
+    Push(Push),
+    Prometheus(prom::Registry),
+}
+
+impl Sink {
+    fn name(&self) -> String {
+        match self {
+            Sink::Stdout(_) => "stdout".to_string(),
+            Sink::File(file) => format!("{:?}", file.config.path),
+            Sink::Push(push) => push.url.clone(),
+            Sink::Prometheus(_) => "prometheus".to_string(),
+        }
+    }
+
+    pub async fn write(&self, produced: &[(FullName, Obs)]) -> Result<()> {
+        match self {
+            Sink::Stdout(format) => {
+                let buf = render(format.writer(), produced)?;
+                let mut stdout = std::io::stdout().lock();
+                stdout.write_all(&buf)?;
+                stdout.flush()?;
+            }
+            Sink::File(file) => file.append(&render(file.config.format.writer(), produced)?)?,
+            Sink::Push(push) => push.write(produced).await?,
+            Sink::Prometheus(registry) => registry.update(produced, Utc::now()),
+        }
+        Ok(())
+    }
+}
+
+/// Every configured sink; one failing doesn't stop the rest being written to.
+pub struct Sinks(Vec<Sink>);
+
+impl Sinks {
+    /// `poll` is the longest interval between polls when running as a daemon; without it
+    /// the Prometheus server isn't started, as it would be pointless.
+    pub async fn new(
+        http: &Client,
+        configs: Vec<config::Sink>,
+        poll: Option<Duration>,
+    ) -> Result<Self> {
+        let mut sinks = Vec::with_capacity(configs.len());
+        for config in configs {
+            sinks.push(match config {
+                config::Sink::Stdout { format } => Sink::Stdout(format),
+                config::Sink::File(file) => Sink::File(File { config: file }),
+                config::Sink::Vm(vm) => Sink::Push(Push::new(http.clone(), vm)),
+                config::Sink::Influx(influx) => Sink::Push(Push::influx(http.clone(), influx)?),
+                config::Sink::Prometheus(prometheus) => {
+                    let Some(poll) = poll else {
+                        info!("not serving metrics, only done in daemon mode");
+                        continue;
+                    };
+                    let listener = prom::bind(prometheus.listen).await?;
+                    let registry = prom::Registry::new(poll * prom::EXPIRE_POLLS);
+                    tokio::spawn(prom::serve(listener, registry.clone()));
+                    Sink::Prometheus(registry)
+                }
+            });
+        }
+        if sinks.is_empty() {
+            sinks.push(Sink::Stdout(Format::Vm));
+        }
+        Ok(Sinks(sinks))
+    }
+
+    /// Fails only if every sink does.
+    pub async fn write(&self, produced: &[(FullName, Obs)]) -> Result<()> {
+        let mut failed = 0;
+        for sink in &self.0 {
+            if let Err(e) = sink.write(produced).await {
+                warn!("writing to {} failed: {e:?}", sink.name());
+                failed += 1;
+            }
+        }
+        ensure!(failed < self.0.len(), "writing to every sink failed");
+        Ok(())
+    }
+}
+
+/// Appends to a file, rotating it when it's full.
+pub struct File {
+    config: config::File,
+}
+
+impl File {
+    fn append(&self, buf: &[u8]) -> Result<()> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let path = &self.config.path;
+        let len = match fs::metadata(path) {
+            Ok(meta) => meta.len(),
+            Err(e) if e.kind() == ErrorKind::NotFound => 0,
+            Err(e) => return Err(e).with_context(|| anyhow!("checking {path:?}")),
+        };
+        if len > 0 && len + buf.len() as u64 > self.config.max_bytes {
+            self.rotate()
+                .with_context(|| anyhow!("rotating {path:?}"))?;
+        }
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut f| f.write_all(buf))
+            .with_context(|| anyhow!("appending to {path:?}"))
+    }
+
+    /// `path.2` to `path.3`, `path.1` to `path.2`, `path` to `path.1`, dropping the last.
+    fn rotate(&self) -> Result<()> {
+        let path = &self.config.path;
+        let numbered = |n: u32| {
+            let mut name = path.as_os_str().to_owned();
+            name.push(format!(".{n}"));
+            PathBuf::from(name)
+        };
+        let keep = self.config.keep;
+        if keep == 0 {
+            fs::remove_file(path)?;
+            return Ok(());
+        }
+        for n in (1..keep).rev() {
+            match fs::rename(numbered(n), numbered(n + 1)) {
+                Ok(()) => (),
+                Err(e) if e.kind() == ErrorKind::NotFound => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        fs::rename(path, numbered(1))?;
+        Ok(())
+    }
+}
+
+/// Sends metrics to VictoriaMetrics' `/api/v1/import` or InfluxDB's `/api/v2/write`.
+pub struct Push {
+    http: Client,
+    url: String,
+    auth: Auth,
+    writer: &'static dyn Writer,
+    /// where to keep what couldn't be delivered, to be sent with the next batch
+    spool: Option<PathBuf>,
+    spool_max_bytes: u64,
+    attempts: u32,
+    retry_delay: Duration,
+}
+
+/// How a `Push` says who it is.
+enum Auth {
+    None,
+    Basic {
+        user: String,
+        password: Option<String>,
+    },
+    Bearer(String),
+    /// InfluxDB's `Authorization: Token ...`
+    Token(String),
+}
+
+impl Push {
+    pub fn new(http: Client, config: config::Vm) -> Self {
+        let auth = match (config.bearer, config.user) {
+            (Some(token), _) => Auth::Bearer(token),
+            (None, Some(user)) => Auth::Basic {
+                user,
+                password: config.password,
+            },
+            (None, None) => Auth::None,
+        };
+        Push {
+            http,
+            url: config.url,
+            auth,
+            writer: &VmJson,
+            spool: config.spool,
+            spool_max_bytes: config.spool_max_bytes,
+            attempts: config.attempts,
+            retry_delay: Duration::from_millis(config.retry_delay_ms),
+        }
+    }
+
+    pub fn influx(http: Client, config: config::Influx) -> Result<Self> {
+        let base = config.url.trim_end_matches('/');
+        let url = reqwest::Url::parse_with_params(
+            &format!("{base}/api/v2/write"),
+            [
+                ("org", config.org.as_str()),
+                ("bucket", config.bucket.as_str()),
+                ("precision", "ms"),
+            ],
+        )
+        .with_context(|| anyhow!("parsing influx url {:?}", config.url))?;
+        Ok(Push {
+            http,
+            url: url.to_string(),
+            auth: Auth::Token(config.token),
+            writer: &InfluxLine,
+            spool: config.spool,
+            spool_max_bytes: config.spool_max_bytes,
+            attempts: config.attempts,
+            retry_delay: Duration::from_millis(config.retry_delay_ms),
+        })
+    }
+
+    /// Render `produced` in the server's format, and `send` it.
+    pub async fn write(&self, produced: &[(FullName, Obs)]) -> Result<()> {
+        self.send(&render(self.writer, produced)?).await
+    }
+
+    /// Deliver `buf`, along with anything left in the spool from previous failures.
+    ///
+    /// If the server can't be reached after all attempts, `buf` is added to the spool (if
+    /// configured), dropping the oldest lines beyond `spool_max_bytes`, and this returns
+    /// `Ok`. If the server rejects the batch, retrying won't help. The spooled lines and
+    /// `buf` are then sent apart, so only whichever is rejected is appended to
+    /// `spool.rejected` to be looked at, and this fails.
+    pub async fn send(&self, buf: &[u8]) -> Result<()> {
+        let mut body = match &self.spool {
+            Some(path) => match fs::read(path) {
+                Ok(spooled) => spooled,
+                Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+                Err(e) => return Err(e).with_context(|| anyhow!("reading spool {path:?}")),
+            },
+            None => Vec::new(),
+        };
+        let spooled = body.len();
+        body.extend_from_slice(buf);
+
+        if body.is_empty() {
+            return Ok(());
+        }
+
+        let err = match self.post_with_retry(&body).await {
+            Ok(()) => return self.clear_spool(),
+            Err(e) => e,
+        };
+        let Some(path) = &self.spool else {
+            return Err(err);
+        };
+
+        if !err.is::<Rejected>() {
+            warn!("spooling {} bytes to {path:?}: {err:?}", buf.len());
+            return fs::write(path, self.capped(&body))
+                .with_context(|| anyhow!("writing spool {path:?}"));
+        }
+
+        let results = match body.split_at(spooled) {
+            ([], buf) => vec![(buf, Err(err))],
+            (spooled, buf) => {
+                let mut results = Vec::with_capacity(2);
+                for part in [spooled, buf].into_iter().filter(|p| !p.is_empty()) {
+                    results.push((part, self.post_with_retry(part).await));
+                }
+                results
+            }
+        };
+        let mut unsent = Vec::new();
+        let mut rejected = None;
+        for (part, result) in results {
+            match result {
+                Ok(()) => (),
+                Err(e) if e.is::<Rejected>() => {
+                    self.set_aside(path, part)?;
+                    rejected = Some(e);
+                }
+                Err(e) => {
+                    warn!("spooling {} bytes to {path:?}: {e:?}", part.len());
+                    unsent.extend_from_slice(part);
+                }
+            }
+        }
+        if unsent.is_empty() {
+            self.clear_spool()?;
+        } else {
+            fs::write(path, self.capped(&unsent))
+                .with_context(|| anyhow!("writing spool {path:?}"))?;
+        }
+        rejected.map_or(Ok(()), Err)
+    }
+
+    /// Append a rejected batch to `spool.rejected`, after any earlier ones.
+    fn set_aside(&self, spool: &Path, batch: &[u8]) -> Result<()> {
+        let mut aside = spool.as_os_str().to_owned();
+        aside.push(".rejected");
+        let aside = PathBuf::from(aside);
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&aside)
+            .and_then(|mut f| f.write_all(self.capped(batch)))
+            .with_context(|| anyhow!("setting aside rejected batch in {aside:?}"))
+    }
+
+    fn clear_spool(&self) -> Result<()> {
+        let Some(path) = &self.spool else {
+            return Ok(());
+        };
+        match fs::remove_file(path) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e).with_context(|| anyhow!("clearing spool {path:?}")),
+        }
+    }
+
+    /// The last whole lines of `buf` which fit in `spool_max_bytes`.
+    fn capped<'a>(&self, buf: &'a [u8]) -> &'a [u8] {
+        let max = usize::try_from(self.spool_max_bytes).unwrap_or(usize::MAX);
+        if buf.len() <= max {
+            return buf;
+        }
+        let from = buf.len() - max;
+        let start = match buf[from - 1..].iter().position(|&b| b == b'\n') {
+            Some(i) => from + i,
+            None => buf.len(),
+        };
+        warn!("spool full, dropping the oldest {start} bytes");
+        &buf[start..]
+    }
+
+    async fn post_with_retry(&self, body: &[u8]) -> Result<()> {
+        let mut delay = self.retry_delay;
+        let mut attempt = 1;
+        loop {
+            let err = match self.post(body.to_vec()).await {
+                Ok(()) => return Ok(()),
+                Err(e) if e.is::<Rejected>() => return Err(e),
+                Err(e) => e,
+            };
+            if attempt >= self.attempts {
+                return Err(err.context(format!("giving up after {attempt} attempts")));
+            }
+            warn!("push attempt {attempt} failed, retrying in {delay:?}: {err:?}");
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+            attempt += 1;
+        }
+    }
+
+    async fn post(&self, body: Vec<u8>) -> Result<()> {
+        let req = self.http.post(&self.url).body(body);
+        let req = match &self.auth {
+            Auth::None => req,
+            Auth::Basic { user, password } => req.basic_auth(user, password.as_ref()),
+            Auth::Bearer(token) => req.bearer_auth(token),
+            Auth::Token(token) => req.header("Authorization", format!("Token {token}")),
+        };
+        let resp = req.send().await?;
+        let status = resp.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        let text = resp.text().await.unwrap_or_default();
+        // a timeout or being told to slow down are worth retrying; other client errors aren't
+        let retry = [StatusCode::REQUEST_TIMEOUT, StatusCode::TOO_MANY_REQUESTS];
+        if status.is_client_error() && !retry.contains(&status) {
+            return Err(Rejected { status, text }.into());
+        }
+        bail!("write failed: {status}: {text}");
+    }
+}
+
+/// The server refused a batch, so sending it again won't help.
+#[derive(Debug)]
+struct Rejected {
+    status: StatusCode,
+    text: String,
+}
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "write rejected: {}: {}", self.status, self.text)
+    }
 }
 
-pub fn write_metric(mut write: impl Write, name: &FullName, obs: &[Obs]) -> Result<()> {
-    let values = obs.iter().map(|o| o.value).collect::<Vec<_>>();
-    let timestamps = obs.iter().map(|o| o.timestamp).collect::<Vec<_>>();
+impl std::error::Error for Rejected {}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use anyhow::Result;
+    use chrono::DateTime;
+    use reqwest::Client;
+
+    use super::{render, Format, FullName, InfluxLine, Obs, Push, Sink, Sinks, VmJson, Writer};
+    use crate::config;
+    use crate::testing::stand_in;
+
+    fn vm_config(base: String, name: &str) -> config::Vm {
+        let spool =
+            std::env::temp_dir().join(format!("disport-spool-{}-{name}.jsonl", std::process::id()));
+        let _ = fs::remove_file(&spool);
+        config::Vm {
+            url: format!("{base}/api/v1/import"),
+            user: None,
+            password: None,
+            bearer: Some("sekrit".to_string()),
+            spool: Some(spool),
+            spool_max_bytes: 1024,
+            attempts: 2,
+            retry_delay_ms: 1,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_push() -> Result<()> {
+        let (url, rx) = stand_in(&[(200, "")])?;
+        let push = super::Push::new(Client::new(), vm_config(url, "push"));
+        push.send(b"{\"a\":1}\n").await?;
+        let (headers, body) = rx.recv()?;
+        assert!(
+            headers
+                .to_ascii_lowercase()
+                .contains("authorization: bearer sekrit"),
+            "{headers}"
+        );
+        assert_eq!(body, "{\"a\":1}\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_full_name() -> Result<()> {
+        let name = FullName::new("owm_temp-c", [("0station", "x"), ("a.b", "any thing")])?;
+        assert_eq!(name.name(), "owm_temp_c");
+        assert_eq!(
+            name.labels().collect::<Vec<_>>(),
+            [("_0station", "x"), ("a_b", "any thing")]
+        );
+        assert_eq!(FullName::plain("job:rate")?.name(), "job:rate");
+        assert_eq!(FullName::plain("1h")?.name(), "_1h");
+
+        assert!(FullName::plain("").is_err());
+        assert!(FullName::plain("soliscloud_soliscloud_ac_power_w").is_err());
+        assert!(FullName::new("a", [("__name__", "b")]).is_err());
+        assert!(FullName::new("a", [("", "b")]).is_err());
+        let mut name = FullName::plain("a")?;
+        assert!(name.add_label("a:b", "c").is_ok());
+        assert_eq!(name.label("a_b"), Some("c"));
+        assert!(name.add_label("__x", "c").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_influx() -> Result<()> {
+        let name = FullName::new(
+            "ac_power_w",
+            [("site", "my home"), ("id", "1,2"), ("empty", "")],
+        )?;
+        let mut buf = Vec::new();
+        InfluxLine.write(
+            &mut buf,
+            &name,
+            &[
+                Obs::new(1.5, DateTime::from_timestamp_millis(1_000).unwrap()),
+                Obs::new(f64::NAN, DateTime::from_timestamp_millis(2_000).unwrap()),
+                Obs::new(2., DateTime::from_timestamp_millis(3_000).unwrap()),
+            ],
+        )?;
+        assert_eq!(
+            String::from_utf8(buf)?,
+            "ac_power_w,id=1\\,2,site=my\\ home value=1.5 1000\n\
+             ac_power_w,id=1\\,2,site=my\\ home value=2 3000\n"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_influx_push() -> Result<()> {
+        let (url, rx) = stand_in(&[(204, "")])?;
+        let push = Push::influx(
+            Client::new(),
+            config::Influx {
+                url: format!("{url}/"),
+                org: "home".to_string(),
+                bucket: "solar data".to_string(),
+                token: "sekrit".to_string(),
+                spool: None,
+                spool_max_bytes: 1024,
+                attempts: 1,
+                retry_delay_ms: 1,
+            },
+        )?;
+        let when = DateTime::from_timestamp_millis(1_000).unwrap();
+        push.write(&[(FullName::plain("a")?, Obs::new(1., when))])
+            .await?;
+        let (headers, body) = rx.recv()?;
+        assert!(
+            headers.starts_with("POST /api/v2/write?org=home&bucket=solar+data&precision=ms "),
+            "{headers}"
+        );
+        assert!(headers
+            .to_ascii_lowercase()
+            .contains("authorization: token sekrit"));
+        assert_eq!(body, "a value=1 1000\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_render() -> Result<()> {
+        let at = |ms| Obs::new(0., DateTime::from_timestamp_millis(ms).unwrap());
+        let a = || FullName::new("a", [("site", "home")]).unwrap();
+        let produced = [
+            (a(), at(2_000)),
+            (FullName::plain("b")?, at(1_000)),
+            (a(), at(1_000)),
+        ];
+        assert_eq!(
+            String::from_utf8(render(&VmJson, &produced)?)?,
+            "{\"metric\":{\"__name__\":\"a\",\"site\":\"home\"},\"timestamps\":[2000,1000],\"values\":[0.0,0.0]}\n\
+             {\"metric\":{\"__name__\":\"b\"},\"timestamps\":[1000],\"values\":[0.0]}\n"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sinks() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("disport-sinks-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir)?;
+        let path = dir.join("metrics.jsonl");
+
+        // two attempts for each of three writes
+        let (url, _rx) = stand_in(&[(500, ""); 6])?;
+        let mut broken = vm_config(url, "sinks");
+        broken.spool = None;
+        let sinks = Sinks::new(
+            &Client::new(),
+            vec![
+                config::Sink::Vm(broken),
+                config::Sink::File(config::File {
+                    path: path.clone(),
+                    format: Format::Influx,
+                    max_bytes: 40,
+                    keep: 1,
+                }),
+                config::Sink::Prometheus(config::Prometheus {
+                    listen: "127.0.0.1:0".parse()?,
+                }),
+            ],
+            None,
+        )
+        .await?;
+        assert_eq!(sinks.0.len(), 2);
 
-    serde_json::to_writer(
-        &mut write,
-        &json!({
-            "metric": name.0,
-            "values": values,
-            "timestamps": timestamps,
-        }),
-    )?;
-    write.write_all(b"\n")?;
+        let when = DateTime::from_timestamp_millis(1_000).unwrap();
+        let produced = [(FullName::plain("a")?, Obs::new(1., when))];
+        sinks.write(&produced).await?;
+        assert_eq!(fs::read_to_string(&path)?, "a value=1 1000\n");
 
-    Ok(())
+        sinks.write(&produced).await?;
+        sinks.write(&produced).await?;
+        assert_eq!(fs::read_to_string(&path)?, "a value=1 1000\n");
+        assert_eq!(
+            fs::read_to_string(dir.join("metrics.jsonl.1"))?,
+            "a value=1 1000\na value=1 1000\n"
+        );
+        assert!(!dir.join("metrics.jsonl.2").exists());
+
+        let (url, _rx) = stand_in(&[(500, "")])?;
+        let mut broken = vm_config(url, "sinks-all");
+        broken.spool = None;
+        broken.attempts = 1;
+        let sinks = Sinks::new(&Client::new(), vec![config::Sink::Vm(broken)], None).await?;
+        assert!(sinks.write(&produced).await.is_err());
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sinks_isolated() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("disport-isolated-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir)?;
+        let file = |name: &str| {
+            config::Sink::File(config::File {
+                path: dir.join(name),
+                format: Format::Influx,
+                max_bytes: 1024,
+                keep: 1,
+            })
+        };
+
+        // the one in the middle fails, and the others still get the batch
+        let (url, rx) = stand_in(&[(500, "")])?;
+        let mut broken = vm_config(url, "isolated");
+        broken.spool = None;
+        broken.attempts = 1;
+        let sinks = Sinks::new(
+            &Client::new(),
+            vec![file("a"), config::Sink::Vm(broken), file("b")],
+            None,
+        )
+        .await?;
+        let when = DateTime::from_timestamp_millis(1_000).unwrap();
+        sinks
+            .write(&[(FullName::plain("a")?, Obs::new(1., when))])
+            .await?;
+        assert_eq!(rx.iter().count(), 1);
+        for name in ["a", "b"] {
+            assert_eq!(fs::read_to_string(dir.join(name))?, "a value=1 1000\n");
+        }
+
+        let sinks = Sinks::new(&Client::new(), vec![], None).await?;
+        assert!(matches!(sinks.0[..], [Sink::Stdout(Format::Vm)]));
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_spool() -> Result<()> {
+        let (url, rx) = stand_in(&[(503, ""), (500, ""), (200, "")])?;
+        let config = vm_config(url, "spool");
+        let spool = config.spool.clone().expect("configured");
+        let push = super::Push::new(Client::new(), config);
+
+        push.send(b"first\n").await?;
+        assert_eq!(rx.recv()?.1, "first\n");
+        assert_eq!(rx.recv()?.1, "first\n");
+        assert_eq!(fs::read_to_string(&spool)?, "first\n");
+
+        push.send(b"second\n").await?;
+        assert_eq!(rx.recv()?.1, "first\nsecond\n");
+        assert!(!spool.exists());
+
+        // only whole lines, and the newest, are kept
+        let (url, _rx) = stand_in(&[(500, ""), (500, ""), (500, ""), (500, "")])?;
+        let mut config = vm_config(url, "spool-cap");
+        config.spool_max_bytes = 10;
+        let spool = config.spool.clone().expect("configured");
+        let push = super::Push::new(Client::new(), config);
+        push.send(b"first\n").await?;
+        push.send(b"second\n").await?;
+        assert_eq!(fs::read_to_string(&spool)?, "second\n");
+        fs::remove_file(&spool)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rejected() -> Result<()> {
+        let (url, rx) = stand_in(&[
+            (503, ""),
+            (500, ""),
+            (400, "bad line"),
+            (200, ""),
+            (400, "bad line"),
+            (400, "bad line"),
+            (200, ""),
+        ])?;
+        let config = vm_config(url, "rejected");
+        let spool = config.spool.clone().expect("configured");
+        let aside = spool.with_extension("jsonl.rejected");
+        let _ = fs::remove_file(&aside);
+        let push = super::Push::new(Client::new(), config);
+
+        push.send(b"first\n").await?;
+        // the spooled line was fine, and goes through on its own
+        assert!(push.send(b"bad\n").await.is_err());
+        let bodies = rx.iter().take(5).map(|(_, body)| body).collect::<Vec<_>>();
+        assert_eq!(bodies[2..], ["first\nbad\n", "first\n", "bad\n"]);
+        assert!(!spool.exists());
+        assert_eq!(fs::read_to_string(&aside)?, "bad\n");
+
+        // later rejections don't overwrite earlier ones
+        assert!(push.send(b"worse\n").await.is_err());
+        assert_eq!(rx.recv()?.1, "worse\n");
+        assert_eq!(fs::read_to_string(&aside)?, "bad\nworse\n");
+
+        push.send(b"third\n").await?;
+        assert_eq!(rx.recv()?.1, "third\n");
+        fs::remove_file(&aside)?;
+        Ok(())
+    }
 }